@@ -0,0 +1,321 @@
+//! Looking for problems without building anything.
+//! That's `cargo check` and `cargo clippy` for the Rust,
+//! `elm make` into `/dev/null` for each Elm program,
+//! and `elm-format --validate`, if it's installed.
+//! Every tool reports problems its own way, so they all get
+//! turned into [`Diagnostic`]s, and listed together by file.
+use crate::config::Config;
+use crate::runner::{self, StepError};
+use crate::OutputMethod;
+use ::serde::Serialize;
+use ::serde_json::Value;
+use ::std::collections::BTreeMap;
+use ::std::path::{Path, PathBuf};
+use ::std::process::{Command, Output};
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Severity {
+    Error,
+    Warning,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Diagnostic {
+    pub(crate) tool: &'static str,
+    pub(crate) severity: Severity,
+    /// Absolute, when we know it. Some problems aren't about any one file.
+    pub(crate) file: Option<PathBuf>,
+    pub(crate) line: Option<u64>,
+    pub(crate) column: Option<u64>,
+    pub(crate) message: String,
+    /// Like `E0308`, or `clippy::needless_borrow`.
+    pub(crate) code: Option<String>,
+}
+
+/// Whatever ran, whether or not it was happy about it.
+/// Tools that find problems exit unhappily, but that's no reason to stop.
+fn output(result: Result<Output, StepError>) -> Result<(Output, bool), StepError> {
+    match result {
+        Ok(x) => Ok((x, true)),
+        Err(StepError::Failed { output, .. }) => Ok((output, false)),
+        Err(e) => Err(e),
+    }
+}
+
+/// A tool failed without telling us why in a way we understand,
+/// so all we can do is pass on what it said.
+fn unexplained(tool: &'static str, output: &Output) -> Diagnostic {
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    Diagnostic {
+        tool,
+        severity: Severity::Error,
+        file: None,
+        line: None,
+        column: None,
+        message: format!("failed ({})\n{}", output.status, stderr.trim_end()),
+        code: None,
+    }
+}
+
+/// `cargo check` or `cargo clippy`, reading its JSON messages.
+fn cargo(
+    project_root: &Path,
+    subcommand: &'static str,
+    tool: &'static str,
+) -> ::anyhow::Result<Vec<Diagnostic>> {
+    let mut c = Command::new("cargo");
+    c.arg(subcommand)
+        .arg("--workspace")
+        .arg("--all-targets")
+        .arg("--message-format=json")
+        .arg("--manifest-path")
+        .arg(project_root.join("Cargo.toml"));
+    let step = format!("cargo {}", subcommand);
+    let (out, success) = output(runner::run(&step, &mut c, OutputMethod::Capture))?;
+    let mut diagnostics = Vec::new();
+    for line in String::from_utf8_lossy(&out.stdout).lines() {
+        let message: Value = match ::serde_json::from_str(line) {
+            Ok(x) => x,
+            Err(_) => continue,
+        };
+        if message["reason"] != "compiler-message" {
+            continue;
+        }
+        let message = &message["message"];
+        let severity = match message["level"].as_str() {
+            Some("error") => Severity::Error,
+            Some("warning") => Severity::Warning,
+            // Notes and help come attached to one of those.
+            _ => continue,
+        };
+        let text = message["message"].as_str().unwrap_or_default();
+        // Just the count at the end, which we do ourselves.
+        if message["spans"].as_array().is_some_and(|x| x.is_empty())
+            && (text.ends_with("warnings emitted")
+                || text.ends_with("warning emitted")
+                || text.starts_with("aborting due to"))
+        {
+            continue;
+        }
+        let span = message["spans"]
+            .as_array()
+            .and_then(|x| x.iter().find(|s| s["is_primary"] == true));
+        diagnostics.push(Diagnostic {
+            tool,
+            severity,
+            file: span
+                .and_then(|s| s["file_name"].as_str())
+                .map(|x| project_root.join(x)),
+            line: span.and_then(|s| s["line_start"].as_u64()),
+            column: span.and_then(|s| s["column_start"].as_u64()),
+            message: text.into(),
+            code: message["code"]["code"].as_str().map(String::from),
+        });
+    }
+    if !success && diagnostics.iter().all(|x| x.severity != Severity::Error) {
+        diagnostics.push(unexplained(tool, &out));
+    }
+    Ok(diagnostics)
+}
+
+/// Elm's messages are a list of plain strings and styled ones.
+fn elm_message(message: &Value) -> String {
+    match message.as_array() {
+        Some(parts) => parts
+            .iter()
+            .map(|x| match x.as_str() {
+                Some(s) => s,
+                None => x["string"].as_str().unwrap_or_default(),
+            })
+            .collect(),
+        None => message.as_str().unwrap_or_default().into(),
+    }
+}
+
+/// `elm make`, once per entrypoint, reading its JSON report.
+fn elm(config: &Config, elm: &Path) -> ::anyhow::Result<Vec<Diagnostic>> {
+    let project_dir = &config.elm.project_dir;
+    let mut diagnostics = Vec::new();
+    for entry in &config.elm.entrypoints {
+        let mut c = Command::new(elm);
+        c.current_dir(project_dir)
+            .arg("make")
+            .arg(&entry.source)
+            .arg("--output=/dev/null")
+            .arg("--report=json");
+        let step = format!("elm make {}", entry.source.display());
+        let (out, success) = output(runner::run(&step, &mut c, OutputMethod::Capture))?;
+        if success {
+            continue;
+        }
+        let report: Value = match ::serde_json::from_slice(&out.stderr) {
+            Ok(x) => x,
+            Err(_) => {
+                diagnostics.push(unexplained("elm", &out));
+                continue;
+            }
+        };
+        match report["type"].as_str() {
+            Some("compile-errors") => {
+                for error in report["errors"].as_array().into_iter().flatten() {
+                    let file = error["path"].as_str().map(|x| project_dir.join(x));
+                    for problem in error["problems"].as_array().into_iter().flatten() {
+                        let start = &problem["region"]["start"];
+                        diagnostics.push(Diagnostic {
+                            tool: "elm",
+                            severity: Severity::Error,
+                            file: file.clone(),
+                            line: start["line"].as_u64(),
+                            column: start["column"].as_u64(),
+                            message: format!(
+                                "{}\n{}",
+                                problem["title"].as_str().unwrap_or_default(),
+                                elm_message(&problem["message"])
+                            ),
+                            code: None,
+                        });
+                    }
+                }
+            }
+            // Problems with the project, rather than the code, like a bad `elm.json`.
+            _ => diagnostics.push(Diagnostic {
+                tool: "elm",
+                severity: Severity::Error,
+                file: report["path"].as_str().map(|x| project_dir.join(x)),
+                line: None,
+                column: None,
+                message: format!(
+                    "{}\n{}",
+                    report["title"].as_str().unwrap_or_default(),
+                    elm_message(&report["message"])
+                ),
+                code: None,
+            }),
+        }
+    }
+    // Every program importing a broken module reports it again.
+    dedup(&mut diagnostics);
+    Ok(diagnostics)
+}
+
+/// `elm-format --validate`, which lists files that need formatting.
+fn elm_format(config: &Config, elm_format: &Path) -> ::anyhow::Result<Vec<Diagnostic>> {
+    let mut c = Command::new(elm_format);
+    c.current_dir(&config.elm.project_dir)
+        .arg("--validate")
+        .args(crate::elm_source_dirs(config)?);
+    let (out, success) = output(runner::run(
+        "elm-format --validate",
+        &mut c,
+        OutputMethod::Capture,
+    ))?;
+    let mut diagnostics = Vec::new();
+    if let Ok(Value::Array(files)) = ::serde_json::from_slice(&out.stdout) {
+        for file in files {
+            diagnostics.push(Diagnostic {
+                tool: "elm-format",
+                severity: Severity::Warning,
+                file: file["path"]
+                    .as_str()
+                    .map(|x| config.elm.project_dir.join(x)),
+                line: None,
+                column: None,
+                message: file["message"]
+                    .as_str()
+                    .unwrap_or("not formatted with elm-format")
+                    .into(),
+                code: None,
+            });
+        }
+    } else if !success {
+        diagnostics.push(unexplained("elm-format", &out));
+    }
+    Ok(diagnostics)
+}
+
+fn dedup(diagnostics: &mut Vec<Diagnostic>) {
+    let mut seen = Vec::new();
+    diagnostics.retain(|x| {
+        let key = (x.file.clone(), x.line, x.column, x.message.clone());
+        if seen.contains(&key) {
+            false
+        } else {
+            seen.push(key);
+            true
+        }
+    });
+}
+
+/// Run everything, and collect what it all found.
+pub(crate) fn check(
+    project_root: &Path,
+    config: &Config,
+    elm_bin: &Path,
+) -> ::anyhow::Result<Vec<Diagnostic>> {
+    let mut diagnostics = cargo(project_root, "check", "rustc")?;
+    // Clippy repeats everything `cargo check` said, plus its own.
+    diagnostics.extend(cargo(project_root, "clippy", "clippy")?);
+    diagnostics.extend(elm(config, elm_bin)?);
+    if let Ok(x) = ::which::which("elm-format") {
+        diagnostics.extend(elm_format(config, &x)?);
+    }
+    dedup(&mut diagnostics);
+    Ok(diagnostics)
+}
+
+/// One JSON object per line, for editors.
+pub(crate) fn print_json(diagnostics: &[Diagnostic]) -> ::anyhow::Result<()> {
+    for diagnostic in diagnostics {
+        println!("{}", ::serde_json::to_string(diagnostic)?);
+    }
+    Ok(())
+}
+
+/// Everything, grouped by file, for people.
+pub(crate) fn print_human(project_root: &Path, diagnostics: &[Diagnostic]) {
+    let mut by_file: BTreeMap<Option<&Path>, Vec<&Diagnostic>> = BTreeMap::new();
+    for diagnostic in diagnostics {
+        by_file
+            .entry(diagnostic.file.as_deref())
+            .or_default()
+            .push(diagnostic);
+    }
+    for (file, mut diagnostics) in by_file {
+        match file {
+            Some(file) => println!(
+                "{}",
+                file.strip_prefix(project_root).unwrap_or(file).display()
+            ),
+            None => println!("(project)"),
+        }
+        diagnostics.sort_by_key(|x| (x.line, x.column, x.severity));
+        for diagnostic in diagnostics {
+            let position = match (diagnostic.line, diagnostic.column) {
+                (Some(line), Some(column)) => format!("{}:{} ", line, column),
+                (Some(line), None) => format!("{} ", line),
+                _ => String::new(),
+            };
+            let severity = match diagnostic.severity {
+                Severity::Error => "error",
+                Severity::Warning => "warning",
+            };
+            let code = match diagnostic.code {
+                Some(ref x) => format!(" {}", x),
+                None => String::new(),
+            };
+            let mut lines = diagnostic.message.lines();
+            println!(
+                "  {}{}[{}{}]: {}",
+                position,
+                severity,
+                diagnostic.tool,
+                code,
+                lines.next().unwrap_or_default()
+            );
+            for line in lines {
+                println!("      {}", line);
+            }
+        }
+    }
+}
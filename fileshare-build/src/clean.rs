@@ -0,0 +1,136 @@
+//! Removing what builds leave behind, and saying how much that was.
+//! With a dry run, nothing gets removed, but everything gets counted the same.
+//! Anything that's already gone is already clean, and that's fine.
+use crate::runner;
+use crate::OutputMethod;
+use ::std::fs;
+use ::std::path::{Path, PathBuf};
+use ::std::process::Command;
+use ::walkdir::WalkDir;
+
+pub(crate) struct Cleaner {
+    dry_run: bool,
+    freed: u64,
+    /// Everything counted so far, so nothing inside it gets counted twice.
+    counted: Vec<PathBuf>,
+}
+impl Cleaner {
+    pub(crate) fn new(dry_run: bool) -> Self {
+        Self {
+            dry_run,
+            freed: 0,
+            counted: Vec::new(),
+        }
+    }
+
+    /// How much is at `path`, not counting what we've already counted.
+    /// `None` if there's nothing there at all.
+    fn size(&self, path: &Path) -> Option<u64> {
+        fs::symlink_metadata(path).ok()?;
+        if self.counted.iter().any(|x| path.starts_with(x)) {
+            return Some(0);
+        }
+        let counted = &self.counted;
+        Some(
+            WalkDir::new(path)
+                .into_iter()
+                .filter_entry(|x| !counted.iter().any(|c| c == x.path()))
+                .filter_map(Result::ok)
+                .filter(|x| x.file_type().is_file())
+                .filter_map(|x| x.metadata().ok())
+                .map(|x| x.len())
+                .sum(),
+        )
+    }
+
+    /// Remove a file or directory.
+    pub(crate) fn remove(&mut self, what: &str, path: &Path) -> ::anyhow::Result<()> {
+        let size = match self.size(path) {
+            Some(x) => x,
+            None => {
+                println!("{}: already clean", what);
+                return Ok(());
+            }
+        };
+        if self.dry_run {
+            println!(
+                "{}: would remove {} ({})",
+                what,
+                path.display(),
+                bytes(size)
+            );
+        } else {
+            if fs::symlink_metadata(path)?.is_dir() {
+                fs::remove_dir_all(path)?;
+            } else {
+                fs::remove_file(path)?;
+            }
+            println!("{}: removed {} ({})", what, path.display(), bytes(size));
+        }
+        self.freed += size;
+        self.counted.push(path.to_path_buf());
+        Ok(())
+    }
+
+    /// `cargo clean`, which knows best what's Cargo's to remove.
+    pub(crate) fn cargo(&mut self, project_root: &Path, doc: bool) -> ::anyhow::Result<()> {
+        let what = if doc { "rust docs" } else { "rust" };
+        let dir = project_root.join(if doc { crate::doc::DOC_DIR } else { "target" });
+        let before = match self.size(&dir) {
+            Some(x) => x,
+            None => {
+                println!("{}: already clean", what);
+                return Ok(());
+            }
+        };
+        if self.dry_run {
+            println!(
+                "{}: would remove {} ({})",
+                what,
+                dir.display(),
+                bytes(before)
+            );
+            self.freed += before;
+        } else {
+            let mut c = Command::new("cargo");
+            c.arg("clean");
+            if doc {
+                c.arg("--doc");
+            }
+            c.arg("--manifest-path")
+                .arg(project_root.join("Cargo.toml"));
+            runner::run("cargo clean", &mut c, OutputMethod::Forward)?;
+            let freed = before.saturating_sub(self.size(&dir).unwrap_or(0));
+            println!("{}: removed {} ({})", what, dir.display(), bytes(freed));
+            self.freed += freed;
+        }
+        self.counted.push(dir);
+        Ok(())
+    }
+
+    pub(crate) fn summary(&self) -> String {
+        if self.dry_run {
+            format!("would free {}", bytes(self.freed))
+        } else {
+            format!("freed {}", bytes(self.freed))
+        }
+    }
+}
+
+/// Sizes the way people read them.
+fn bytes(n: u64) -> String {
+    const UNITS: &[&str] = &["KiB", "MiB", "GiB", "TiB"];
+    if n < 1024 {
+        return format!("{} B", n);
+    }
+    let mut size = n as f64;
+    let mut unit = "B";
+    for &u in UNITS {
+        if size < 1024.0 {
+            break;
+        }
+        size /= 1024.0;
+        unit = u;
+    }
+    format!("{:.1} {}", size, unit)
+}
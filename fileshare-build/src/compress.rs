@@ -0,0 +1,189 @@
+//! Precompressed copies of the text assets, for release builds.
+//! Other builds remove them, since they'd be older than what they compress.
+//! Every `main.js` gets a `main.js.gz` and a `main.js.br` next to it,
+//! so the server can hand out whichever the browser prefers,
+//! without compressing anything per request.
+use crate::manifest::{relative, Hasher, Manifest};
+use ::std::collections::BTreeSet;
+use ::std::fs;
+use ::std::io::Write;
+use ::std::path::{Path, PathBuf};
+use ::walkdir::WalkDir;
+
+/// Extensions worth compressing.
+/// Images and fonts are already compressed, and would just get bigger.
+/// Source maps aren't served by release builds, so they're left alone too.
+const TEXT_EXTENSIONS: &[&str] = &[
+    "html",
+    "css",
+    "js",
+    "mjs",
+    "json",
+    "svg",
+    "txt",
+    "xml",
+    "webmanifest",
+];
+
+/// The encodings we write, and the extensions they get.
+pub(crate) const ENCODINGS: &[&str] = &["gz", "br"];
+
+fn is_text(path: &Path) -> bool {
+    path.extension()
+        .and_then(|x| x.to_str())
+        .is_some_and(|x| TEXT_EXTENSIONS.contains(&x))
+}
+
+pub(crate) fn sibling(path: &Path, encoding: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".");
+    name.push(encoding);
+    PathBuf::from(name)
+}
+
+fn gzip(data: &[u8]) -> ::std::io::Result<Vec<u8>> {
+    let mut encoder = ::flate2::write::GzEncoder::new(Vec::new(), ::flate2::Compression::best());
+    encoder.write_all(data)?;
+    encoder.finish()
+}
+
+fn brotli(data: &[u8]) -> ::std::io::Result<Vec<u8>> {
+    let mut out = Vec::new();
+    {
+        // Highest quality, and the biggest window the format allows
+        // without the large window extension, which browsers don't support.
+        let mut encoder = ::brotli::CompressorWriter::new(&mut out, 4096, 11, 22);
+        encoder.write_all(data)?;
+    }
+    Ok(out)
+}
+
+/// Write `.gz` and `.br` siblings for every text asset in the output directory,
+/// and clean up any we wrote for assets that are gone.
+/// Returns how many assets got fresh copies.
+pub(crate) fn precompress(output_dir: &Path, manifest: &mut Manifest) -> ::anyhow::Result<usize> {
+    let mut count = 0;
+    for path in text_assets(output_dir)? {
+        let path = path.as_path();
+        let key = format!("compress:{}", relative(manifest.root(), path));
+        let inputs = Hasher::new(manifest.root()).file(path)?.finish();
+        if manifest.is_fresh(&key, &inputs) {
+            continue;
+        }
+        let data = fs::read(path)?;
+        let gz = sibling(path, "gz");
+        let br = sibling(path, "br");
+        fs::write(&gz, gzip(&data)?)?;
+        fs::write(&br, brotli(&data)?)?;
+        manifest.record(&key, inputs, &[&gz, &br])?;
+        count += 1;
+    }
+    // Steps we didn't go through this time are for assets that are gone.
+    for orphan in manifest.forget_stale_steps("compress:") {
+        remove_if_there(&orphan)?;
+    }
+    Ok(count)
+}
+
+/// Remove the `.gz` and `.br` copies we wrote, going by the manifest,
+/// and any next to a text asset, in case the manifest's been lost.
+/// Builds that don't precompress rewrite the originals,
+/// so copies from an earlier release build would be stale.
+/// Anything else, like a `.tar.gz` that's there to be downloaded, stays.
+pub(crate) fn remove_precompressed(
+    output_dir: &Path,
+    manifest: &mut Manifest,
+) -> ::anyhow::Result<usize> {
+    let mut copies: BTreeSet<PathBuf> = manifest.forget_steps("compress:").into_iter().collect();
+    for path in text_assets(output_dir)? {
+        copies.extend(ENCODINGS.iter().map(|x| sibling(&path, x)));
+    }
+    let mut count = 0;
+    for copy in copies {
+        if remove_if_there(&copy)? {
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+fn text_assets(output_dir: &Path) -> ::walkdir::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in WalkDir::new(output_dir) {
+        let entry = entry?;
+        if entry.file_type().is_file() && is_text(entry.path()) {
+            files.push(entry.into_path());
+        }
+    }
+    Ok(files)
+}
+
+/// Whether there was anything to remove.
+fn remove_if_there(path: &Path) -> ::std::io::Result<bool> {
+    match fs::remove_file(path) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == ::std::io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn release_then_debug_leaves_no_stale_copies() {
+        let root = ::tempfile::tempdir().unwrap();
+        let output_dir = root.path().join("static");
+        fs::create_dir_all(&output_dir).unwrap();
+        let main = output_dir.join("main.js");
+        let mut manifest = Manifest::load(root.path(), false);
+
+        // A release build.
+        fs::write(&main, "release").unwrap();
+        assert_eq!(precompress(&output_dir, &mut manifest).unwrap(), 1);
+        assert!(sibling(&main, "gz").is_file());
+        assert!(sibling(&main, "br").is_file());
+
+        // A debug build rewrites the original, and nothing's left to serve
+        // in its place.
+        fs::write(&main, "debug").unwrap();
+        assert_eq!(remove_precompressed(&output_dir, &mut manifest).unwrap(), 2);
+        assert!(!sibling(&main, "gz").exists());
+        assert!(!sibling(&main, "br").exists());
+        assert!(main.is_file());
+
+        // The next release build has to compress it again.
+        fs::write(&main, "release").unwrap();
+        assert_eq!(precompress(&output_dir, &mut manifest).unwrap(), 1);
+    }
+
+    #[test]
+    fn other_compressed_files_are_left_alone() {
+        let root = ::tempfile::tempdir().unwrap();
+        let output_dir = root.path().join("static");
+        fs::create_dir_all(&output_dir).unwrap();
+        let main = output_dir.join("main.js");
+        let download = output_dir.join("release.tar.gz");
+        let mut manifest = Manifest::load(root.path(), false);
+        fs::write(&main, "release").unwrap();
+        fs::write(&download, "not really gzip").unwrap();
+
+        precompress(&output_dir, &mut manifest).unwrap();
+        assert!(download.is_file());
+
+        // Once `main.js` is gone, so are its copies, but not the download.
+        manifest.save().unwrap();
+        let mut manifest = Manifest::load(root.path(), false);
+        fs::remove_file(&main).unwrap();
+        assert_eq!(precompress(&output_dir, &mut manifest).unwrap(), 0);
+        assert!(!sibling(&main, "gz").exists());
+        assert!(!sibling(&main, "br").exists());
+        assert!(download.is_file());
+
+        // Debug builds leave it alone too.
+        fs::write(&main, "debug").unwrap();
+        remove_precompressed(&output_dir, &mut manifest).unwrap();
+        assert!(download.is_file());
+    }
+}
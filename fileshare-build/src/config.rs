@@ -0,0 +1,442 @@
+//! The project's `fileshare-build.toml`.
+//! Everything in here is optional, and a missing file
+//! is the same as an empty one.
+//! Paths are relative to the project root.
+use ::serde::Deserialize;
+use ::std::collections::BTreeMap;
+use ::std::path::{Path, PathBuf};
+
+pub(crate) const CONFIG_FILE: &str = "fileshare-build.toml";
+
+#[derive(Debug, ::thiserror::Error)]
+pub(crate) enum ConfigError {
+    #[error("couldn't read {}: {}", .0.display(), .1)]
+    Read(PathBuf, #[source] ::std::io::Error),
+    #[error("invalid {}: {}", .0.display(), .1)]
+    Parse(PathBuf, #[source] ::toml::de::Error),
+    #[error("invalid {}: {}", .0.display(), .1)]
+    Invalid(PathBuf, String),
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Config {
+    pub(crate) build: BuildConfig,
+    pub(crate) elm: ElmConfig,
+    pub(crate) assets: AssetsConfig,
+    pub(crate) minify: MinifyConfig,
+    pub(crate) dev: DevConfig,
+    pub(crate) toolchain: ToolchainConfig,
+    pub(crate) package: PackageConfig,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct BuildConfig {
+    /// Where the sources live. This is also what the dev server watches.
+    pub(crate) source_dir: PathBuf,
+    /// Where the built site goes.
+    /// Note that the Rocket app serves `static/` no matter what,
+    /// so changing this is only useful if something else serves it.
+    pub(crate) output_dir: PathBuf,
+}
+impl Default for BuildConfig {
+    fn default() -> Self {
+        Self {
+            source_dir: "src".into(),
+            output_dir: "static".into(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct ElmConfig {
+    /// The directory with `elm.json` in it.
+    /// `elm make` runs from here, so this is also where `elm-stuff` ends up.
+    pub(crate) project_dir: PathBuf,
+    pub(crate) entrypoints: Vec<Entrypoint>,
+}
+impl Default for ElmConfig {
+    fn default() -> Self {
+        Self {
+            project_dir: ".".into(),
+            entrypoints: vec![Entrypoint {
+                source: "src/Main.elm".into(),
+                output: "main.js".into(),
+            }],
+        }
+    }
+}
+impl ElmConfig {
+    pub(crate) fn elm_stuff(&self) -> PathBuf {
+        self.project_dir.join("elm-stuff")
+    }
+}
+
+/// An Elm program and the bundle it compiles to.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Entrypoint {
+    /// The module with `main` in it.
+    pub(crate) source: PathBuf,
+    /// Relative to the output directory.
+    pub(crate) output: PathBuf,
+}
+
+/// Which source files get copied into the output,
+/// and which files we pretend don't exist at all.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct AssetsConfig {
+    /// Globs for files to copy verbatim into the output.
+    pub(crate) include: Vec<String>,
+    /// Extra ignore patterns, in `.gitignore` syntax.
+    /// These are applied after everything else,
+    /// so a `!pattern` here can bring back something
+    /// the defaults or a `.gitignore` threw out.
+    pub(crate) ignore: Vec<String>,
+    /// Whether to ignore the usual editor droppings.
+    /// See [`crate::rules::DEFAULT_IGNORES`].
+    pub(crate) default_ignores: bool,
+    /// Whether to respect `.gitignore` and `.ignore` files.
+    pub(crate) gitignore: bool,
+}
+impl Default for AssetsConfig {
+    fn default() -> Self {
+        Self {
+            include: vec!["*.html".into(), "*.css".into(), "*.js".into()],
+            ignore: Vec::new(),
+            default_ignores: true,
+            gitignore: true,
+        }
+    }
+}
+
+/// Which minifier to use.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum MinifyBackend {
+    /// Terser if it's installed, the Rust one otherwise,
+    /// if fileshare-build was built with it.
+    Auto,
+    Terser,
+    /// swc's minifier, built in with the `rust-minifier` feature.
+    Rust,
+}
+
+/// Settings for the minifier pass over the Elm output.
+/// The options are spelled the way terser spells them,
+/// whichever minifier ends up using them.
+/// The defaults are the ones the Elm guide recommends.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct MinifyConfig {
+    pub(crate) enabled: bool,
+    pub(crate) backend: MinifyBackend,
+    /// Functions with no side effects, which the compressor may drop
+    /// if their results go unused.
+    pub(crate) pure_funcs: Vec<String>,
+    /// Any other compress options, as terser spells them.
+    pub(crate) compress: Vec<String>,
+    pub(crate) mangle: bool,
+    /// Write a source map next to each minified bundle.
+    pub(crate) source_maps: bool,
+}
+impl Default for MinifyConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            backend: MinifyBackend::Auto,
+            pure_funcs: [
+                "F2", "F3", "F4", "F5", "F6", "F7", "F8", "F9", "A2", "A3", "A4", "A5", "A6", "A7",
+                "A8", "A9",
+            ]
+            .iter()
+            .map(|&x| x.into())
+            .collect(),
+            compress: ["pure_getters", "keep_fargs=false", "unsafe_comps", "unsafe"]
+                .iter()
+                .map(|&x| x.into())
+                .collect(),
+            mangle: true,
+            source_maps: true,
+        }
+    }
+}
+impl MinifyConfig {
+    /// The argument to terser's `--compress`.
+    pub(crate) fn terser_compress(&self) -> String {
+        let mut options = vec![format!("pure_funcs=\"{}\"", self.pure_funcs.join(","))];
+        options.extend(self.compress.iter().cloned());
+        options.join(",")
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct DevConfig {
+    /// Address for the live reload WebSocket.
+    pub(crate) address: String,
+    pub(crate) port: u16,
+    /// For `dev --faults` and `test --faults`.
+    pub(crate) faults: FaultsConfig,
+}
+impl Default for DevConfig {
+    fn default() -> Self {
+        Self {
+            address: "0.0.0.0".into(),
+            port: 9000,
+            faults: FaultsConfig::default(),
+        }
+    }
+}
+
+/// How bad the network in front of the app is, with `--faults`.
+/// Everything's off by default, apart from where the proxy listens.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct FaultsConfig {
+    /// Where the proxy listens in dev mode. It forwards to Rocket's port.
+    pub(crate) port: u16,
+    /// Added to every chunk, each way.
+    pub(crate) latency_ms: u64,
+    /// Up to this much more latency, picked at random for each chunk.
+    pub(crate) jitter_ms: u64,
+    /// Bytes per second, each way, for each connection.
+    pub(crate) bandwidth: Option<u64>,
+    /// The chance that a connection gets reset, for each direction.
+    pub(crate) reset_chance: f64,
+    /// Resets land somewhere in the MiB after this many bytes,
+    /// so they're in the body, rather than the headers.
+    pub(crate) reset_after: u64,
+    /// The chance that a connection stops going anywhere for a while,
+    /// for each direction, somewhere in its first MiB.
+    pub(crate) stall_chance: f64,
+    /// How long stalls last. Zero is forever, or until someone gives up.
+    pub(crate) stall_ms: u64,
+    /// Makes which connections get what the same from run to run.
+    pub(crate) seed: Option<u64>,
+}
+impl Default for FaultsConfig {
+    fn default() -> Self {
+        Self {
+            port: 8001,
+            latency_ms: 0,
+            jitter_ms: 0,
+            bandwidth: None,
+            reset_chance: 0.0,
+            reset_after: 16 * 1024,
+            stall_chance: 0.0,
+            stall_ms: 10_000,
+            seed: None,
+        }
+    }
+}
+
+/// Pinned versions of the tools we shell out to.
+/// Anything not pinned here comes from `PATH`, as before.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct ToolchainConfig {
+    /// Base URL that artifacts get downloaded from.
+    pub(crate) mirror: Option<String>,
+    /// A directory of artifacts, checked before the mirror.
+    /// Handy for working offline.
+    pub(crate) local_dir: Option<PathBuf>,
+    /// Where installed tools go.
+    /// Defaults to `fileshare-build/toolchain` in the user's cache directory.
+    pub(crate) cache_dir: Option<PathBuf>,
+    pub(crate) elm: Option<ToolPin>,
+    pub(crate) terser: Option<ToolPin>,
+}
+
+/// One version of one tool.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct ToolPin {
+    pub(crate) version: String,
+    /// Artifacts by platform, like `linux-x86_64`.
+    pub(crate) platforms: BTreeMap<String, Artifact>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Artifact {
+    /// File name, both on the mirror and in `local_dir`.
+    pub(crate) file: String,
+    /// Hex SHA-256 of the file as downloaded.
+    pub(crate) sha256: String,
+    /// Path to the executable inside the archive.
+    /// Defaults to the tool's name, plus `.exe` on Windows,
+    /// which is right for a lone gzipped binary.
+    pub(crate) bin: Option<PathBuf>,
+}
+
+/// Settings for `package`, and for the server the bundle ends up on.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct PackageConfig {
+    /// Where bundles go.
+    pub(crate) output_dir: PathBuf,
+    /// Where the bundle gets unpacked on the server.
+    /// The systemd unit expects to find everything here.
+    pub(crate) install_dir: PathBuf,
+    /// Who the service runs as.
+    pub(crate) user: String,
+    pub(crate) address: String,
+    pub(crate) port: u16,
+    /// TLS files, on the server.
+    pub(crate) tls_certs: PathBuf,
+    pub(crate) tls_key: PathBuf,
+}
+impl Default for PackageConfig {
+    fn default() -> Self {
+        Self {
+            output_dir: "target/package".into(),
+            install_dir: "/opt/fileshare".into(),
+            user: "fileshare".into(),
+            address: "0.0.0.0".into(),
+            port: 443,
+            tls_certs: "/etc/fileshare/tls/fullchain.cer".into(),
+            tls_key: "/etc/fileshare/tls/key.pem".into(),
+        }
+    }
+}
+
+impl Config {
+    /// Load the config from the project root, if there is one,
+    /// or from `path`, if given, which must exist.
+    /// `path` comes from the command line, so it's taken as is,
+    /// but relative paths in the result are resolved against the project root.
+    pub(crate) fn load(project_root: &Path, path: Option<&Path>) -> Result<Self, ConfigError> {
+        let explicit = path.is_some();
+        let path = match path {
+            Some(x) => x.to_path_buf(),
+            None => project_root.join(CONFIG_FILE),
+        };
+        let mut config: Self = match ::std::fs::read_to_string(&path) {
+            Ok(text) => ::toml::from_str(&text).map_err(|e| ConfigError::Parse(path.clone(), e))?,
+            Err(e) if e.kind() == ::std::io::ErrorKind::NotFound && !explicit => Self::default(),
+            Err(e) => return Err(ConfigError::Read(path, e)),
+        };
+        config
+            .validate()
+            .map_err(|e| ConfigError::Invalid(path, e))?;
+        config.resolve(project_root);
+        Ok(config)
+    }
+
+    /// Catch the mistakes `serde` can't.
+    fn validate(&self) -> Result<(), String> {
+        if self.elm.entrypoints.is_empty() {
+            return Err("`elm.entrypoints` must list at least one program".into());
+        }
+        let mut outputs = ::std::collections::HashSet::new();
+        for entry in &self.elm.entrypoints {
+            if entry.output.is_absolute() {
+                return Err(format!(
+                    "entrypoint output {} must be relative to `build.output_dir`",
+                    entry.output.display()
+                ));
+            }
+            if entry
+                .output
+                .components()
+                .any(|c| c == ::std::path::Component::ParentDir)
+            {
+                return Err(format!(
+                    "entrypoint output {} must stay inside `build.output_dir`",
+                    entry.output.display()
+                ));
+            }
+            if !outputs.insert(&entry.output) {
+                return Err(format!(
+                    "more than one entrypoint writes to {}",
+                    entry.output.display()
+                ));
+            }
+        }
+        if self.dev.port == 0 {
+            return Err("`dev.port` can't be 0; the browser needs to know where to find us".into());
+        }
+        let server_paths = [
+            ("install_dir", &self.package.install_dir),
+            ("tls_certs", &self.package.tls_certs),
+            ("tls_key", &self.package.tls_key),
+        ];
+        for (key, path) in server_paths.iter() {
+            if !path.is_absolute() {
+                return Err(format!(
+                    "`package.{}` is a path on the server, so it must be absolute",
+                    key
+                ));
+            }
+        }
+        let pins = [
+            ("elm", &self.toolchain.elm),
+            ("terser", &self.toolchain.terser),
+        ];
+        for (tool, pin) in pins.iter() {
+            let pin = match pin {
+                Some(x) => x,
+                None => continue,
+            };
+            for (platform, artifact) in &pin.platforms {
+                let valid = artifact.sha256.len() == 64
+                    && artifact.sha256.bytes().all(|b| b.is_ascii_hexdigit());
+                if !valid {
+                    return Err(format!(
+                        "`toolchain.{}.platforms.{}.sha256` must be 64 hex digits",
+                        tool, platform
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn resolve(&mut self, project_root: &Path) {
+        self.build.source_dir = project_root.join(&self.build.source_dir);
+        self.build.output_dir = project_root.join(&self.build.output_dir);
+        self.elm.project_dir = project_root.join(&self.elm.project_dir);
+        for entry in &mut self.elm.entrypoints {
+            entry.source = project_root.join(&entry.source);
+        }
+        self.package.output_dir = project_root.join(&self.package.output_dir);
+        if let Some(ref mut x) = self.toolchain.local_dir {
+            *x = project_root.join(&x);
+        }
+        if let Some(ref mut x) = self.toolchain.cache_dir {
+            *x = project_root.join(&x);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn explicit_config_is_not_under_project_root() {
+        let project = ::tempfile::tempdir().unwrap();
+        let elsewhere = ::tempfile::tempdir().unwrap();
+        let path = elsewhere.path().join("other.toml");
+        ::std::fs::write(&path, "[dev]\nport = 9000\n").unwrap();
+        let config = Config::load(project.path(), Some(&path)).unwrap();
+        assert_eq!(config.dev.port, 9000);
+        assert_eq!(config.build.output_dir, project.path().join("static"));
+    }
+
+    #[test]
+    fn outputs_stay_in_output_dir() {
+        let project = ::tempfile::tempdir().unwrap();
+        let path = project.path().join(CONFIG_FILE);
+        let text = "[[elm.entrypoints]]\nsource = \"src/Main.elm\"\noutput = \"../escape.js\"\n";
+        ::std::fs::write(&path, text).unwrap();
+        match Config::load(project.path(), None) {
+            Err(ConfigError::Invalid(_, e)) => assert!(e.contains("stay inside"), "{}", e),
+            x => panic!("expected it to be rejected, got {:?}", x),
+        }
+    }
+}
@@ -0,0 +1,423 @@
+//! Docs for everything, in one place.
+//! `cargo doc` already writes to `target/doc`,
+//! so the Elm docs go in `target/doc/elm`,
+//! and an `index.html` on top links to all of it.
+//!
+//! `elm make --docs` only works for Elm packages, and ours is an application,
+//! so the Elm sources get copied into a throwaway package to document them.
+//! Packages only take modules that start with a doc comment,
+//! so the ones that don't are just listed, as undocumented.
+use crate::config::Config;
+use crate::runner;
+use crate::OutputMethod;
+use ::serde::Deserialize;
+use ::serde_json::Value;
+use ::std::fmt::Write as _;
+use ::std::fs;
+use ::std::path::{Path, PathBuf};
+use ::std::process::Command;
+use ::walkdir::WalkDir;
+
+/// Where `cargo doc` puts things, relative to the project root.
+pub(crate) const DOC_DIR: &str = "target/doc";
+
+/// Where the Elm docs go, relative to the project root.
+pub(crate) const ELM_DOC_DIR: &str = "target/doc/elm";
+
+/// Directories `cargo doc` writes that aren't crates.
+const NOT_CRATES: &[&str] = &[
+    "src",
+    "elm",
+    "implementors",
+    "static.files",
+    "trait.impl",
+    "type.impl",
+];
+
+/// One module, as `elm make --docs` describes it.
+#[derive(Deserialize)]
+struct ElmModule {
+    name: String,
+    comment: String,
+    #[serde(default)]
+    unions: Vec<ElmUnion>,
+    #[serde(default)]
+    aliases: Vec<ElmItem>,
+    #[serde(default)]
+    values: Vec<ElmItem>,
+}
+
+#[derive(Deserialize)]
+struct ElmUnion {
+    name: String,
+    comment: String,
+    args: Vec<String>,
+    /// Constructor names, and the types they take.
+    cases: Vec<(String, Vec<String>)>,
+}
+
+/// An alias or a value. Both boil down to a name, a type and a comment.
+#[derive(Deserialize)]
+struct ElmItem {
+    name: String,
+    comment: String,
+    #[serde(default)]
+    args: Vec<String>,
+    #[serde(rename = "type")]
+    tipe: String,
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Elm doc comments say where things go with `@docs` lines,
+/// which mean nothing once we've listed everything anyway.
+fn comment(s: &str) -> String {
+    let text = s
+        .lines()
+        .filter(|x| !x.trim_start().starts_with("@docs"))
+        .collect::<Vec<_>>()
+        .join("\n");
+    let text = text.trim();
+    if text.is_empty() {
+        String::new()
+    } else {
+        format!("<pre class=\"comment\">{}</pre>\n", escape(text))
+    }
+}
+
+fn page(title: &str, body: &str) -> String {
+    format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{title}</title>\n\
+         <style>body {{ font-family: sans-serif; max-width: 50em; margin: auto; }} \
+         pre.comment {{ white-space: pre-wrap; font-family: inherit; }} \
+         code {{ background: #f4f4f4; }}</style>\n\
+         </head>\n<body>\n<h1>{title}</h1>\n{body}</body>\n</html>\n",
+        title = escape(title),
+        body = body
+    )
+}
+
+/// The Elm modules' pages, and an index of them.
+fn render_elm(modules: &[ElmModule], out: &Path) -> ::anyhow::Result<()> {
+    let mut index = String::from("<ul>\n");
+    for module in modules {
+        let file = format!("{}.html", module.name.replace('.', "-"));
+        writeln!(
+            index,
+            "<li><a href=\"{}\">{}</a></li>",
+            file,
+            escape(&module.name)
+        )?;
+        let mut body = comment(&module.comment);
+        for union in &module.unions {
+            let cases = union
+                .cases
+                .iter()
+                .map(|(name, args)| {
+                    let mut case = name.clone();
+                    for arg in args {
+                        write!(case, " {}", arg).unwrap();
+                    }
+                    case
+                })
+                .collect::<Vec<_>>();
+            write!(
+                body,
+                "<h3 id=\"{name}\"><code>type {name}{args}{cases}</code></h3>\n{comment}",
+                name = escape(&union.name),
+                args = escape(
+                    &union
+                        .args
+                        .iter()
+                        .map(|x| format!(" {}", x))
+                        .collect::<String>()
+                ),
+                cases = if cases.is_empty() {
+                    String::new()
+                } else {
+                    escape(&format!(" = {}", cases.join(" | ")))
+                },
+                comment = comment(&union.comment)
+            )?;
+        }
+        for alias in &module.aliases {
+            write!(
+                body,
+                "<h3 id=\"{name}\"><code>type alias {name}{args} = {tipe}</code></h3>\n{comment}",
+                name = escape(&alias.name),
+                args = escape(
+                    &alias
+                        .args
+                        .iter()
+                        .map(|x| format!(" {}", x))
+                        .collect::<String>()
+                ),
+                tipe = escape(&alias.tipe),
+                comment = comment(&alias.comment)
+            )?;
+        }
+        for value in &module.values {
+            write!(
+                body,
+                "<h3 id=\"{name}\"><code>{name} : {tipe}</code></h3>\n{comment}",
+                name = escape(&value.name),
+                tipe = escape(&value.tipe),
+                comment = comment(&value.comment)
+            )?;
+        }
+        body.push_str("<p><a href=\"index.html\">All modules</a></p>\n");
+        fs::write(out.join(file), page(&module.name, &body))?;
+    }
+    index.push_str("</ul>\n<p><a href=\"../index.html\">All docs</a></p>\n");
+    fs::write(out.join("index.html"), page("Elm modules", &index))?;
+    Ok(())
+}
+
+/// Whether an Elm module can go in a package's docs:
+/// it has to start with a doc comment, and packages can't have ports.
+fn documented(source: &str) -> bool {
+    let mut offset = 0;
+    let mut declaration = None;
+    for line in source.split_inclusive('\n') {
+        if line.starts_with("module ") || line.starts_with("effect module ") {
+            declaration = Some(offset);
+            break;
+        }
+        if line.starts_with("port module ") {
+            return false;
+        }
+        offset += line.len();
+    }
+    let rest = match declaration {
+        Some(x) => &source[x..],
+        None => return false,
+    };
+    // The doc comment comes right after the exposing list.
+    let open = match rest
+        .find("exposing")
+        .and_then(|x| rest[x..].find('(').map(|y| x + y))
+    {
+        Some(x) => x,
+        None => return false,
+    };
+    let mut depth = 0;
+    for (i, c) in rest[open..].char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => {
+                depth -= 1;
+                if depth == 0 {
+                    return rest[open + i + 1..].trim_start().starts_with("{-|");
+                }
+            }
+            _ => (),
+        }
+    }
+    false
+}
+
+/// `Foo/Bar.elm` is `Foo.Bar`.
+fn module_name(relative: &Path) -> String {
+    relative
+        .with_extension("")
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+/// A package `elm.json` exposing `modules`,
+/// with the application's direct dependencies as ranges.
+fn package_elm_json(app: &Value, modules: &[String]) -> Value {
+    let mut dependencies = ::serde_json::Map::new();
+    if let Some(direct) = app["dependencies"]["direct"].as_object() {
+        for (name, version) in direct {
+            let version = version.as_str().unwrap_or("1.0.0");
+            let major: u64 = version
+                .split('.')
+                .next()
+                .and_then(|x| x.parse().ok())
+                .unwrap_or(1);
+            dependencies.insert(
+                name.clone(),
+                format!("{} <= v < {}.0.0", version, major + 1).into(),
+            );
+        }
+    }
+    ::serde_json::json!({
+        "type": "package",
+        "name": "fileshare/docs",
+        "summary": "Just for the docs.",
+        "license": "BSD-3-Clause",
+        "version": "1.0.0",
+        "exposed-modules": modules,
+        "elm-version": "0.19.0 <= v < 0.20.0",
+        "dependencies": dependencies,
+        "test-dependencies": {},
+    })
+}
+
+/// Build the Elm docs, for the modules that have any.
+/// Returns the names of the ones that don't.
+fn elm(config: &Config, elm: &Path, out: &Path) -> ::anyhow::Result<Vec<String>> {
+    let app: Value = ::serde_json::from_slice(&fs::read(config.elm.project_dir.join("elm.json"))?)?;
+    // Packages keep everything in `src`.
+    let package = ::tempfile::Builder::new()
+        .prefix("fileshare-elm-docs")
+        .tempdir()?;
+    let src = package.path().join("src");
+    let mut documented_modules = Vec::new();
+    let mut undocumented = Vec::new();
+    for dir in crate::elm_source_dirs(config)? {
+        for entry in WalkDir::new(&dir) {
+            let entry = entry?;
+            let path = entry.path();
+            if !entry.file_type().is_file()
+                || path.extension().and_then(|x| x.to_str()) != Some("elm")
+            {
+                continue;
+            }
+            let relative = path.strip_prefix(&dir)?;
+            let source = fs::read_to_string(path)?;
+            if documented(&source) {
+                documented_modules.push(module_name(relative));
+            } else {
+                undocumented.push(module_name(relative));
+            }
+            fs::create_dir_all(src.join(relative).parent().unwrap_or(&src))?;
+            fs::write(src.join(relative), source)?;
+        }
+    }
+    documented_modules.sort();
+    undocumented.sort();
+    if documented_modules.is_empty() {
+        return Ok(undocumented);
+    }
+    fs::write(
+        package.path().join("elm.json"),
+        ::serde_json::to_vec_pretty(&package_elm_json(&app, &documented_modules))?,
+    )?;
+    fs::create_dir_all(out)?;
+    let docs_json = out.join("docs.json");
+    let mut c = Command::new(elm);
+    c.current_dir(package.path())
+        .arg("make")
+        .arg(format!("--docs={}", docs_json.display()));
+    runner::run("elm make --docs", &mut c, OutputMethod::Forward)?;
+    let modules: Vec<ElmModule> = ::serde_json::from_slice(&fs::read(&docs_json)?)?;
+    render_elm(&modules, out)?;
+    Ok(undocumented)
+}
+
+/// The crates `cargo doc` documented.
+fn crates(doc_dir: &Path) -> ::anyhow::Result<Vec<String>> {
+    let mut crates = Vec::new();
+    for entry in fs::read_dir(doc_dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if NOT_CRATES.contains(&name.as_str()) || !entry.path().join("index.html").is_file() {
+            continue;
+        }
+        crates.push(name);
+    }
+    crates.sort();
+    Ok(crates)
+}
+
+/// Build all the docs. Returns the path to the index.
+pub(crate) fn doc(
+    project_root: &Path,
+    config: &Config,
+    elm_bin: &Path,
+) -> ::anyhow::Result<PathBuf> {
+    let mut c = Command::new("cargo");
+    c.arg("doc")
+        .arg("--workspace")
+        .arg("--no-deps")
+        .arg("--manifest-path")
+        .arg(project_root.join("Cargo.toml"));
+    runner::run("cargo doc", &mut c, OutputMethod::Forward)?;
+
+    let doc_dir = project_root.join(DOC_DIR);
+    let elm_dir = project_root.join(ELM_DOC_DIR);
+    // Whatever was there is out of date now, one way or another.
+    if elm_dir.exists() {
+        fs::remove_dir_all(&elm_dir)?;
+    }
+    let undocumented = elm(config, elm_bin, &elm_dir)?;
+
+    let mut body = String::from("<h2>Rust</h2>\n<ul>\n");
+    for name in crates(&doc_dir)? {
+        writeln!(
+            body,
+            "<li><a href=\"{name}/index.html\">{name}</a></li>",
+            name = escape(&name)
+        )?;
+    }
+    body.push_str("</ul>\n<h2>Elm</h2>\n");
+    if elm_dir.join("index.html").is_file() {
+        body.push_str("<p><a href=\"elm/index.html\">Elm modules</a></p>\n");
+    }
+    if !undocumented.is_empty() {
+        body.push_str(
+            "<p>These don't start with a doc comment, \
+             or are port modules, so there aren't any docs for them:</p>\n<ul>\n",
+        );
+        for name in &undocumented {
+            writeln!(body, "<li><code>{}</code></li>", escape(name))?;
+        }
+        body.push_str("</ul>\n");
+    }
+    let index = doc_dir.join("index.html");
+    fs::write(&index, page("Docs", &body))?;
+    Ok(index)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_modules_with_doc_comments_are_documented() {
+        assert!(documented(
+            "module Upload exposing (Upload, start)\n\n{-| Uploads.\n\n@docs Upload, start\n-}\n"
+        ));
+        assert!(documented(
+            "-- Comments first are fine.\nmodule A.B exposing\n    ( Model\n    , Msg(..)\n    )\n{-| Hi. -}\n"
+        ));
+        assert!(!documented(
+            "module Main exposing (..)\n\nimport Browser\n\n{-| Not the module's. -}\nmain = 1\n"
+        ));
+        assert!(!documented(
+            "port module Ports exposing (send)\n{-| Ports can't go in packages. -}\n"
+        ));
+        assert!(!documented("-- Not a module at all.\n"));
+    }
+
+    #[test]
+    fn package_takes_the_direct_dependencies_as_ranges() {
+        let app = ::serde_json::json!({
+            "type": "application",
+            "dependencies": {
+                "direct": { "elm/core": "1.0.5", "elm/html": "1.0.0" },
+                "indirect": { "elm/virtual-dom": "1.0.2" },
+            },
+        });
+        let package = package_elm_json(&app, &["A.B".into()]);
+        assert_eq!(package["type"], "package");
+        assert_eq!(package["exposed-modules"], ::serde_json::json!(["A.B"]));
+        assert_eq!(
+            package["dependencies"],
+            ::serde_json::json!({
+                "elm/core": "1.0.5 <= v < 2.0.0",
+                "elm/html": "1.0.0 <= v < 2.0.0",
+            })
+        );
+        assert_eq!(module_name(Path::new("A/B.elm")), "A.B");
+    }
+}
@@ -0,0 +1,381 @@
+//! End to end tests, against the real app over real HTTP.
+//! The app gets started from its debug build, on a port nobody's using,
+//! and stopped afterwards.
+//!
+//! For now, this only checks that the site, the bundles and the API get served.
+//! The app doesn't take uploads yet, so there's no upload, share and download
+//! flow to test. Its cases go here once there are endpoints for them.
+//!
+//! With `--faults`, every request goes through the fault injection proxy,
+//! as set up in `[dev.faults]`, to see what survives a bad network.
+//! Requests are retried when they get cut off, like a browser would,
+//! so only a network too bad to get through at all fails the suite.
+//! Unless `[dev.faults]` sets a seed, it's [`SEED`], so a failure
+//! happens again the next time, rather than being flaky.
+use crate::config::{Config, FaultsConfig};
+use crate::faults;
+use crate::log;
+use crate::testing::{Outcome, Report};
+use ::std::io::{self, Read, Write};
+use ::std::net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream};
+use ::std::path::Path;
+use ::std::process::{Child, Command, Stdio};
+use ::std::sync::Arc;
+use ::std::time::{Duration, Instant};
+use ::tokio_rustls::rustls;
+
+const SUITE: &str = "e2e";
+
+/// How long the app gets to start listening.
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How many times a request gets tried, through the fault injection proxy.
+const ATTEMPTS: u32 = 5;
+
+/// The fault injection proxy's seed, if the config doesn't pick one.
+const SEED: u64 = 38;
+
+/// The app's certs are for the real domain, or self signed,
+/// and either way we're talking to localhost.
+struct AcceptAnyCert;
+impl rustls::ServerCertVerifier for AcceptAnyCert {
+    fn verify_server_cert(
+        &self,
+        _roots: &rustls::RootCertStore,
+        _presented_certs: &[rustls::Certificate],
+        _dns_name: ::webpki::DNSNameRef<'_>,
+        _ocsp_response: &[u8],
+    ) -> Result<rustls::ServerCertVerified, rustls::TLSError> {
+        Ok(rustls::ServerCertVerified::assertion())
+    }
+}
+
+struct Response {
+    status: u16,
+    /// Names are lowercased.
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+impl Response {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+/// Just enough of an HTTP client to poke at the app.
+struct Client {
+    port: u16,
+    tls: Option<Arc<rustls::ClientConfig>>,
+    /// Tries at each request, before giving up on it.
+    attempts: u32,
+}
+impl Client {
+    fn get(&self, path: &str) -> io::Result<Response> {
+        let mut attempt = 1;
+        loop {
+            match self.try_get(path) {
+                Err(e) if attempt < self.attempts => {
+                    log::debug!("e2e", "GET {} (attempt {}): {}", path, attempt, e);
+                    attempt += 1;
+                }
+                x => return x,
+            }
+        }
+    }
+
+    fn try_get(&self, path: &str) -> io::Result<Response> {
+        let tcp = TcpStream::connect((Ipv4Addr::LOCALHOST, self.port))?;
+        tcp.set_read_timeout(Some(Duration::from_secs(10)))?;
+        // HTTP/1.0, so the response ends when the connection does,
+        // and we don't need to deal with chunking.
+        let request = format!("GET {} HTTP/1.0\r\nHost: localhost\r\n\r\n", path);
+        let mut raw = Vec::new();
+        match self.tls {
+            Some(ref config) => {
+                let name = ::webpki::DNSNameRef::try_from_ascii_str("localhost")
+                    .expect("localhost is a valid DNS name");
+                let session = rustls::ClientSession::new(config, name);
+                let mut stream = rustls::StreamOwned::new(session, tcp);
+                stream.write_all(request.as_bytes())?;
+                read_until_closed(&mut stream, &mut raw)?;
+            }
+            None => {
+                let mut stream = tcp;
+                stream.write_all(request.as_bytes())?;
+                read_until_closed(&mut stream, &mut raw)?;
+            }
+        }
+        parse_response(&raw)
+    }
+}
+
+/// Servers don't always say goodbye properly over TLS,
+/// which is fine once we've got the whole response.
+fn read_until_closed<R: Read>(stream: &mut R, buf: &mut Vec<u8>) -> io::Result<()> {
+    match stream.read_to_end(buf) {
+        Ok(_) => Ok(()),
+        Err(e)
+            if !buf.is_empty()
+                && matches!(
+                    e.kind(),
+                    io::ErrorKind::ConnectionAborted
+                        | io::ErrorKind::ConnectionReset
+                        | io::ErrorKind::UnexpectedEof
+                ) =>
+        {
+            Ok(())
+        }
+        Err(e) => Err(e),
+    }
+}
+
+fn parse_response(raw: &[u8]) -> io::Result<Response> {
+    let invalid = |what: &str| io::Error::new(io::ErrorKind::InvalidData, what.to_string());
+    let split = raw
+        .windows(4)
+        .position(|x| x == b"\r\n\r\n")
+        .ok_or_else(|| invalid("no end of headers"))?;
+    let head = String::from_utf8_lossy(&raw[..split]);
+    let mut lines = head.split("\r\n");
+    let status = lines
+        .next()
+        .and_then(|x| x.split_whitespace().nth(1))
+        .and_then(|x| x.parse().ok())
+        .ok_or_else(|| invalid("bad status line"))?;
+    let headers = lines
+        .filter_map(|x| {
+            let i = x.find(':')?;
+            Some((x[..i].trim().to_ascii_lowercase(), x[i + 1..].trim().into()))
+        })
+        .collect();
+    let response = Response {
+        status,
+        headers,
+        body: raw[split + 4..].to_vec(),
+    };
+    // The connection closing is how the body ends, so a reset
+    // partway through is only noticeable from this.
+    let length = response
+        .header("content-length")
+        .and_then(|x| x.parse().ok());
+    match length {
+        Some(x) if response.body.len() < x => Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!("got {} of {} bytes", response.body.len(), x),
+        )),
+        _ => Ok(response),
+    }
+}
+
+/// Whether `Rocket.toml` turns on TLS.
+fn uses_tls(project_root: &Path) -> bool {
+    ::std::fs::read_to_string(project_root.join("Rocket.toml"))
+        .ok()
+        .and_then(|x| x.parse::<::toml::Value>().ok())
+        .and_then(|x| x.get("global")?.get("tls").cloned())
+        .is_some()
+}
+
+/// A port that was free a moment ago.
+fn free_port() -> io::Result<u16> {
+    Ok(TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?
+        .local_addr()?
+        .port())
+}
+
+/// The app, killed when we're done with it, however that happens.
+struct App(Child);
+impl Drop for App {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+/// Start the app, with its output going to `log`.
+fn start_app(project_root: &Path, server: &Path, port: u16, log: &Path) -> ::anyhow::Result<App> {
+    let log = ::std::fs::File::create(log)?;
+    let child = Command::new(server)
+        .current_dir(project_root)
+        .env("ROCKET_ENV", "development")
+        .env("ROCKET_ADDRESS", "127.0.0.1")
+        .env("ROCKET_PORT", port.to_string())
+        .stdout(log.try_clone()?)
+        .stderr(log)
+        .stdin(Stdio::null())
+        .spawn()?;
+    let mut app = App(child);
+    let start = Instant::now();
+    loop {
+        if TcpStream::connect((Ipv4Addr::LOCALHOST, port)).is_ok() {
+            return Ok(app);
+        }
+        if let Some(status) = app.0.try_wait()? {
+            ::anyhow::bail!("the app exited ({}) before it started listening", status);
+        }
+        if start.elapsed() > STARTUP_TIMEOUT {
+            ::anyhow::bail!(
+                "the app didn't start listening within {:?}",
+                STARTUP_TIMEOUT
+            );
+        }
+        ::std::thread::sleep(Duration::from_millis(100));
+    }
+}
+
+/// Start the fault injection proxy in front of the app on `upstream`,
+/// returning the port it's listening on.
+/// It has its own thread, and lasts until we exit.
+fn start_faults(upstream: u16, faults: FaultsConfig) -> io::Result<u16> {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+    listener.set_nonblocking(true)?;
+    let port = listener.local_addr()?.port();
+    let mut runtime = ::tokio::runtime::Runtime::new()?;
+    let listener = runtime.enter(|| ::tokio::net::TcpListener::from_std(listener))?;
+    let upstream = SocketAddr::from((Ipv4Addr::LOCALHOST, upstream));
+    ::std::thread::spawn(move || runtime.block_on(faults::proxy(listener, upstream, faults)));
+    Ok(port)
+}
+
+/// Check a response, turning any complaint into a failure.
+fn check(
+    response: io::Result<Response>,
+    status: u16,
+    content_type: Option<&str>,
+    body: Option<&str>,
+) -> Outcome {
+    let response = match response {
+        Ok(x) => x,
+        Err(e) => return Outcome::Failed(format!("request failed: {}", e)),
+    };
+    if response.status != status {
+        return Outcome::Failed(format!("expected {}, got {}", status, response.status));
+    }
+    if let Some(expected) = content_type {
+        let actual = response.header("content-type").unwrap_or("");
+        if !actual.contains(expected) {
+            return Outcome::Failed(format!(
+                "expected content type {}, got {:?}",
+                expected, actual
+            ));
+        }
+    }
+    if let Some(expected) = body {
+        let actual = String::from_utf8_lossy(&response.body);
+        if !actual.contains(expected) {
+            return Outcome::Failed(format!("expected the body to contain {:?}", expected));
+        }
+    }
+    Outcome::Passed
+}
+
+/// Run the suite against a site that's already built, and the app at `server`.
+/// With `faults`, through the fault injection proxy.
+pub(crate) fn run(
+    report: &mut Report,
+    project_root: &Path,
+    server: &Path,
+    config: &Config,
+    faults: bool,
+) {
+    // Just for the app's output, so there's something to show if it dies.
+    let scratch = match ::tempfile::Builder::new().prefix("fileshare-e2e").tempdir() {
+        Ok(x) => x,
+        Err(e) => {
+            report.run(SUITE, "start app", || {
+                Outcome::Failed(format!("couldn't make a directory for its log: {}", e))
+            });
+            return;
+        }
+    };
+    let log = scratch.path().join("app.log");
+    let mut app = None;
+    let mut port = 0;
+    report.run(SUITE, "start app", || {
+        let started = free_port().map_err(::anyhow::Error::from).and_then(|p| {
+            port = p;
+            start_app(project_root, server, p, &log)
+        });
+        match started {
+            Ok(x) => {
+                app = Some(x);
+                Outcome::Passed
+            }
+            Err(e) => {
+                let log = ::std::fs::read_to_string(&log).unwrap_or_default();
+                Outcome::Failed(format!("{}\n{}", e, log))
+            }
+        }
+    });
+    if app.is_none() {
+        return;
+    }
+    if faults {
+        let mut proxied = false;
+        let seed = config.dev.faults.seed.unwrap_or(SEED);
+        let proxy = FaultsConfig {
+            seed: Some(seed),
+            ..config.dev.faults.clone()
+        };
+        log::info!(
+            "e2e",
+            "through the fault injection proxy: {}, seed {}",
+            faults::describe(&proxy),
+            seed
+        );
+        report.run(
+            SUITE,
+            "start fault injection proxy",
+            || match start_faults(port, proxy) {
+                Ok(x) => {
+                    port = x;
+                    proxied = true;
+                    Outcome::Passed
+                }
+                Err(e) => Outcome::Failed(e.to_string()),
+            },
+        );
+        if !proxied {
+            return;
+        }
+    }
+
+    let client = Client {
+        port,
+        tls: if uses_tls(project_root) {
+            let mut tls = rustls::ClientConfig::new();
+            tls.dangerous()
+                .set_certificate_verifier(Arc::new(AcceptAnyCert));
+            Some(Arc::new(tls))
+        } else {
+            None
+        },
+        attempts: if faults { ATTEMPTS } else { 1 },
+    };
+    report.run(SUITE, "GET / serves the site", || {
+        check(client.get("/"), 200, Some("text/html"), None)
+    });
+    for entry in &config.elm.entrypoints {
+        let path = format!(
+            "/{}",
+            entry
+                .output
+                .components()
+                .map(|c| c.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/")
+        );
+        report.run(SUITE, &format!("GET {} serves the bundle", path), || {
+            check(client.get(&path), 200, Some("javascript"), None)
+        });
+    }
+    report.run(SUITE, "GET /api answers", || {
+        check(client.get("/api"), 200, None, Some("Hello, world!"))
+    });
+    report.run(SUITE, "GET of a missing file is a 404", || {
+        check(client.get("/no-such-file.txt"), 404, None, None)
+    });
+}
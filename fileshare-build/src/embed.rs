@@ -0,0 +1,88 @@
+//! Baking the built site into the server binary.
+//! We write out a Rust file listing every asset, with an `include_bytes!`
+//! for it and for each of its precompressed copies,
+//! going by the same list of files `package` bundles,
+//! and build the app with its `embed-assets` feature,
+//! which `include!`s that file instead of reading `static/` at runtime.
+use crate::manifest::{relative, Manifest};
+use crate::package::{is_precompressed, site_files};
+use ::std::collections::BTreeSet;
+use ::std::fmt::Write as _;
+use ::std::fs;
+use ::std::path::{Path, PathBuf};
+
+/// Where the generated code goes, relative to the project root.
+pub(crate) const EMBED_PATH: &str = "target/fileshare-build/embedded_assets.rs";
+
+/// The environment variable the app finds the generated code through.
+pub(crate) const EMBED_ENV: &str = "FILESHARE_EMBEDDED_ASSETS";
+
+/// The app's cargo feature for serving embedded assets.
+pub(crate) const EMBED_FEATURE: &str = "embed-assets";
+
+/// How much of the hash goes into the ETag.
+const ETAG_LENGTH: usize = 16;
+
+fn sibling(files: &BTreeSet<PathBuf>, path: &Path, encoding: &str) -> Option<String> {
+    let path = crate::compress::sibling(path, encoding);
+    if files.contains(&path) {
+        Some(format!(
+            "Some(include_bytes!({:?}))",
+            path.to_string_lossy()
+        ))
+    } else {
+        None
+    }
+}
+
+/// Write the code embedding what this build put in `output_dir`.
+/// Returns how many assets it covers.
+pub(crate) fn generate(
+    project_root: &Path,
+    output_dir: &Path,
+    manifest: &Manifest,
+) -> ::anyhow::Result<usize> {
+    let (files, assets) = site_files(output_dir, manifest);
+    let fingerprinted: BTreeSet<&String> = assets.values().collect();
+    let mut code = String::from(
+        "// Generated by fileshare-build. Don't edit; it'll just get overwritten.\n\
+         pub static ASSETS: &[EmbeddedAsset] = &[\n",
+    );
+    let mut count = 0;
+    for path in &files {
+        // These go in with what they're copies of.
+        if is_precompressed(path) {
+            continue;
+        }
+        let name = relative(output_dir, path);
+        let hash = crate::manifest::hash_file(path)?;
+        writeln!(
+            code,
+            "    EmbeddedAsset {{\n        \
+                 name: {:?},\n        \
+                 etag: {:?},\n        \
+                 fingerprinted: {},\n        \
+                 identity: include_bytes!({:?}),\n        \
+                 gzip: {},\n        \
+                 brotli: {},\n    \
+             }},",
+            name,
+            &hash[..ETAG_LENGTH],
+            fingerprinted.contains(&name),
+            path.to_string_lossy(),
+            sibling(&files, path, "gz").as_deref().unwrap_or("None"),
+            sibling(&files, path, "br").as_deref().unwrap_or("None"),
+        )?;
+        count += 1;
+    }
+    code.push_str("];\n");
+
+    // Cargo goes by modification times,
+    // so leaving an unchanged file alone saves a rebuild.
+    let dest = project_root.join(EMBED_PATH);
+    if fs::read_to_string(&dest).ok().as_deref() != Some(code.as_str()) {
+        ::fsio::file::ensure_exists(&dest).map_err(|e| ::anyhow::anyhow!(e))?;
+        fs::write(&dest, code)?;
+    }
+    Ok(count)
+}
@@ -0,0 +1,236 @@
+//! A bad network, on purpose.
+//! This is a TCP proxy that sits in front of the app, and makes its connections
+//! slow, narrow, and unreliable, as set up in [`FaultsConfig`].
+//! The point is to see how the app, and the pages talking to it, cope with that,
+//! without having to find a bad network.
+//! The app doesn't take uploads yet, so there's nothing to resume,
+//! and `test --faults` only checks that the site still gets through.
+//! Upload and download round trips go in the e2e suite once there are
+//! endpoints for them.
+//!
+//! It works below TLS, so it doesn't know about requests,
+//! just how many bytes have gone each way.
+//! Each direction of each connection decides up front whether it's getting
+//! reset, or stalled, and where. Resets are real ones, with an `RST`,
+//! since that's what a dropped connection looks like.
+use crate::config::FaultsConfig;
+use crate::log;
+use ::rand::rngs::StdRng;
+use ::rand::{Rng, SeedableRng};
+use ::std::io;
+use ::std::net::SocketAddr;
+use ::std::time::{Duration, Instant};
+use ::tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use ::tokio::net::{TcpListener, TcpStream};
+
+/// Resets and stalls land somewhere in this many bytes.
+const SPREAD: u64 = 1024 * 1024;
+/// The most that's read at once.
+const CHUNK: usize = 16 * 1024;
+/// How many chunks can be on their way at once, each way.
+const QUEUE: usize = 64;
+
+/// What's going to go wrong with one direction of a connection.
+#[derive(Debug)]
+struct Plan {
+    reset_at: Option<u64>,
+    stall_at: Option<u64>,
+}
+
+/// How a direction finished.
+#[derive(Debug, PartialEq, Eq)]
+enum Flow {
+    Done,
+    Reset,
+}
+
+/// Describe what's being done, briefly.
+pub(crate) fn describe(faults: &FaultsConfig) -> String {
+    let mut what = Vec::new();
+    if faults.latency_ms > 0 || faults.jitter_ms > 0 {
+        what.push(format!(
+            "{}ms latency (+{}ms jitter)",
+            faults.latency_ms, faults.jitter_ms
+        ));
+    }
+    if let Some(bandwidth) = faults.bandwidth {
+        what.push(format!("{} bytes/s", bandwidth));
+    }
+    if faults.reset_chance > 0.0 {
+        what.push(format!("{:.0}% resets", faults.reset_chance * 100.0));
+    }
+    if faults.stall_chance > 0.0 {
+        what.push(format!("{:.0}% stalls", faults.stall_chance * 100.0));
+    }
+    if what.is_empty() {
+        "no faults".into()
+    } else {
+        what.join(", ")
+    }
+}
+
+/// Forward everything `listener` gets to `upstream`, badly, forever.
+pub(crate) async fn proxy(mut listener: TcpListener, upstream: SocketAddr, faults: FaultsConfig) {
+    let mut rng = match faults.seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_entropy(),
+    };
+    loop {
+        let (client, peer) = match listener.accept().await {
+            Ok(x) => x,
+            Err(e) => {
+                log::warning!("faults", "couldn't accept a connection: {}", e);
+                continue;
+            }
+        };
+        // Each connection gets its own, so what happens to one
+        // doesn't depend on how the others are going.
+        let rng = StdRng::seed_from_u64(rng.gen());
+        let faults = faults.clone();
+        ::tokio::spawn(async move {
+            if let Err(e) = connection(client, peer, upstream, faults, rng).await {
+                log::debug!("faults", "{}: {}", peer, e);
+            }
+        });
+    }
+}
+
+fn plan(faults: &FaultsConfig, rng: &mut StdRng) -> Plan {
+    let mut at = |chance: f64, after: u64| {
+        if chance > 0.0 && rng.gen_bool(chance.min(1.0)) {
+            Some(after + rng.gen_range(0, SPREAD))
+        } else {
+            None
+        }
+    };
+    Plan {
+        reset_at: at(faults.reset_chance, faults.reset_after),
+        stall_at: at(faults.stall_chance, 0),
+    }
+}
+
+async fn connection(
+    mut client: TcpStream,
+    peer: SocketAddr,
+    upstream: SocketAddr,
+    faults: FaultsConfig,
+    mut rng: StdRng,
+) -> io::Result<()> {
+    let mut server = TcpStream::connect(upstream).await?;
+    let up = plan(&faults, &mut rng);
+    let down = plan(&faults, &mut rng);
+    log::debug!("faults", "{}: up {:?}, down {:?}", peer, up, down);
+    let reset = {
+        let (client_read, client_write) = client.split();
+        let (server_read, server_write) = server.split();
+        let up = pump(
+            client_read,
+            server_write,
+            &faults,
+            up,
+            StdRng::seed_from_u64(rng.gen()),
+        );
+        let down = pump(
+            server_read,
+            client_write,
+            &faults,
+            down,
+            StdRng::seed_from_u64(rng.gen()),
+        );
+        ::tokio::pin!(up, down);
+        // If one side's done, the other might not be.
+        // If one side resets, that's the whole connection.
+        ::tokio::select! {
+            x = &mut up => x? == Flow::Reset || down.await? == Flow::Reset,
+            x = &mut down => x? == Flow::Reset || up.await? == Flow::Reset,
+        }
+    };
+    if reset {
+        log::info!("faults", "{}: reset", peer);
+        // No lingering means closing sends an `RST`, rather than a `FIN`.
+        client.set_linger(Some(Duration::from_secs(0)))?;
+        server.set_linger(Some(Duration::from_secs(0)))?;
+    }
+    Ok(())
+}
+
+/// Copy one way, until the reading side's done, or the plan says otherwise.
+/// Reading and writing are separate, so latency delays each chunk
+/// from when it arrived, rather than adding up.
+async fn pump<R: AsyncRead + Unpin, W: AsyncWrite + Unpin>(
+    mut read: R,
+    mut write: W,
+    faults: &FaultsConfig,
+    mut plan: Plan,
+    mut rng: StdRng,
+) -> io::Result<Flow> {
+    // Smaller chunks at low bandwidths, so it trickles, rather than bursts.
+    let chunk = match faults.bandwidth {
+        Some(x) => (x as usize / 10).clamp(1, CHUNK),
+        None => CHUNK,
+    };
+    // Bounded, so a slow writer slows down reading too, like a real network.
+    let (mut tx, mut rx) = ::tokio::sync::mpsc::channel::<(Instant, Vec<u8>)>(QUEUE);
+    let reader = async move {
+        let mut buf = vec![0; chunk];
+        loop {
+            let n = read.read(&mut buf).await?;
+            if n == 0 {
+                return Ok::<_, io::Error>(());
+            }
+            let latency = faults.latency_ms + rng.gen_range(0, faults.jitter_ms + 1);
+            let due = Instant::now() + Duration::from_millis(latency);
+            if tx.send((due, buf[..n].to_vec())).await.is_err() {
+                return Ok(());
+            }
+        }
+    };
+    let writer = async move {
+        let start = Instant::now();
+        let mut sent = 0u64;
+        while let Some((due, bytes)) = rx.recv().await {
+            ::tokio::time::delay_until(due.into()).await;
+            let mut rest = &bytes[..];
+            while !rest.is_empty() {
+                // Stop right where the plan says, not at the end of the chunk.
+                let mut take = rest.len();
+                if let Some(at) = plan.reset_at.into_iter().chain(plan.stall_at).min() {
+                    take = take.min((at - sent) as usize);
+                }
+                write.write_all(&rest[..take]).await?;
+                rest = &rest[take..];
+                sent += take as u64;
+                if let Some(bandwidth) = faults.bandwidth {
+                    // Wait until the average is back down to the limit.
+                    let due = Duration::from_secs_f64(sent as f64 / bandwidth.max(1) as f64);
+                    if let Some(wait) = due.checked_sub(start.elapsed()) {
+                        ::tokio::time::delay_for(wait).await;
+                    }
+                }
+                if plan.reset_at == Some(sent) {
+                    return Ok(Flow::Reset);
+                }
+                if plan.stall_at == Some(sent) {
+                    plan.stall_at = None;
+                    log::info!("faults", "stalling after {} bytes", sent);
+                    if faults.stall_ms == 0 {
+                        ::futures::future::pending::<()>().await;
+                    } else {
+                        ::tokio::time::delay_for(Duration::from_millis(faults.stall_ms)).await;
+                    }
+                }
+            }
+        }
+        write.shutdown().await?;
+        Ok(Flow::Done)
+    };
+    ::tokio::pin!(reader, writer);
+    // The writer's what decides when this direction's done.
+    ::tokio::select! {
+        x = &mut writer => x,
+        x = &mut reader => {
+            x?;
+            writer.await
+        }
+    }
+}
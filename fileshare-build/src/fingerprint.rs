@@ -0,0 +1,365 @@
+//! Content hashed asset names, for release builds.
+//! Every asset in the output gets a copy named after its contents,
+//! like `main.3f9a1c2b.js`, and the HTML is rewritten to point at those.
+//! Since the name changes whenever the contents do,
+//! the server can tell browsers to cache them forever.
+//!
+//! The mapping from plain names to fingerprinted ones goes in
+//! `asset-manifest.json`, which the server reads to know
+//! which files are safe to cache that way.
+use ::std::collections::BTreeMap;
+use ::std::fs;
+use ::std::path::{Component, Path, PathBuf};
+use ::walkdir::WalkDir;
+
+pub(crate) const ASSET_MANIFEST: &str = "asset-manifest.json";
+
+/// How much of the hash goes into the name.
+const HASH_LENGTH: usize = 8;
+
+/// Plain name to fingerprinted name, both relative to the output directory,
+/// with forward slashes.
+pub(crate) type AssetMap = BTreeMap<String, String>;
+
+/// Whether a file is one we rewrite, rather than fingerprint.
+fn is_html(path: &Path) -> bool {
+    path.extension().is_some_and(|x| x == "html")
+}
+
+/// `a/b/c.js` relative to `root`, with forward slashes no matter the platform.
+fn logical_name(root: &Path, path: &Path) -> String {
+    path.strip_prefix(root)
+        .unwrap_or(path)
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// `dir/name.ext` becomes `dir/name.hash.ext`.
+fn fingerprinted_name(name: &str, hash: &str) -> String {
+    let (dir, file) = match name.rfind('/') {
+        Some(i) => name.split_at(i + 1),
+        None => ("", name),
+    };
+    match file.find('.') {
+        // Leading dots don't count, so `.well-known` stays put.
+        Some(i) if i > 0 => format!("{}{}.{}{}", dir, &file[..i], hash, &file[i..]),
+        _ => format!("{}{}.{}", dir, file, hash),
+    }
+}
+
+pub(crate) fn read_asset_map(output_dir: &Path) -> AssetMap {
+    fs::read(output_dir.join(ASSET_MANIFEST))
+        .ok()
+        .and_then(|x| ::serde_json::from_slice(&x).ok())
+        .unwrap_or_default()
+}
+
+/// Everything the last [`fingerprint`] added to the output directory,
+/// that's still there: the fingerprinted copies, compressed or not,
+/// and the asset manifest.
+pub(crate) fn fingerprinted_files(output_dir: &Path) -> Vec<PathBuf> {
+    let mut names: Vec<String> = read_asset_map(output_dir).into_values().collect();
+    names.push(ASSET_MANIFEST.into());
+    let mut files = Vec::new();
+    for name in names {
+        let path = output_dir.join(name);
+        for encoding in crate::compress::ENCODINGS {
+            files.push(crate::compress::sibling(&path, encoding));
+        }
+        files.push(path);
+    }
+    files.retain(|x| x.is_file());
+    files
+}
+
+/// Fingerprint everything in the output directory,
+/// rewrite the HTML to match, and write the asset manifest.
+pub(crate) fn fingerprint(output_dir: &Path) -> ::anyhow::Result<AssetMap> {
+    // Clear out the last round, so they don't pile up.
+    // Anything that hasn't changed will just get written again.
+    for old in read_asset_map(output_dir).values() {
+        let _ = fs::remove_file(output_dir.join(old));
+    }
+
+    // Find everything first, since the copies go in the same directories,
+    // and a walk might or might not turn them up as it goes.
+    let mut files = Vec::new();
+    for entry in WalkDir::new(output_dir).sort_by_file_name() {
+        let entry = entry?;
+        if entry.file_type().is_file() {
+            files.push(entry.into_path());
+        }
+    }
+
+    let mut assets = AssetMap::new();
+    let mut pages = Vec::new();
+    for path in &files {
+        let path = path.as_path();
+        let name = logical_name(output_dir, path);
+        let precompressed = path
+            .extension()
+            .and_then(|x| x.to_str())
+            .is_some_and(|x| crate::compress::ENCODINGS.contains(&x));
+        // Precompressed copies get redone from the fingerprinted files.
+        // Source maps are found through the bundle, by their plain names,
+        // and debug builds are the only ones that serve them anyway.
+        let source_map = path.extension().is_some_and(|x| x == "map");
+        if name == ASSET_MANIFEST || precompressed || source_map {
+            continue;
+        }
+        if is_html(path) {
+            pages.push(path.to_path_buf());
+            continue;
+        }
+        let hash = crate::manifest::hash_file(path)?;
+        let fingerprinted = fingerprinted_name(&name, &hash[..HASH_LENGTH]);
+        fs::copy(path, output_dir.join(&fingerprinted))?;
+        assets.insert(name, fingerprinted);
+    }
+
+    for page in pages {
+        let dir = page.parent().unwrap_or(output_dir);
+        let html = fs::read_to_string(&page)?;
+        let rewritten = rewrite_html(&html, &logical_name(output_dir, dir), &assets);
+        if rewritten != html {
+            fs::write(&page, rewritten)?;
+        }
+    }
+
+    fs::write(
+        output_dir.join(ASSET_MANIFEST),
+        ::serde_json::to_vec_pretty(&assets)?,
+    )?;
+    Ok(assets)
+}
+
+/// Resolve `reference`, as written in a page in `dir`,
+/// to a name relative to the output directory.
+/// `None` if it points outside the output, or off site.
+fn resolve(dir: &str, reference: &str) -> Option<String> {
+    // Anything with a scheme, like `https:` or `data:`, isn't ours.
+    if reference.starts_with("//") || reference.contains(':') {
+        return None;
+    }
+    let (base, rest) = match reference.strip_prefix('/') {
+        Some(rest) => (PathBuf::new(), rest),
+        None => (PathBuf::from(dir), reference),
+    };
+    let mut parts: Vec<String> = base
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy().into_owned())
+        .collect();
+    for c in Path::new(rest).components() {
+        match c {
+            Component::Normal(x) => parts.push(x.to_string_lossy().into_owned()),
+            Component::ParentDir => {
+                parts.pop()?;
+            }
+            _ => (),
+        }
+    }
+    Some(parts.join("/"))
+}
+
+/// Point `src` and `href` attributes at fingerprinted names.
+/// This is nowhere near a real HTML parser,
+/// but it doesn't have to be, for HTML we wrote ourselves.
+fn rewrite_html(html: &str, dir: &str, assets: &AssetMap) -> String {
+    let mut out = String::with_capacity(html.len());
+    let mut rest = html;
+    loop {
+        let lower = rest.to_ascii_lowercase();
+        let found = ["src=", "href="]
+            .iter()
+            .filter_map(|attr| {
+                lower.match_indices(attr).find(|(i, _)| {
+                    // Make sure it's the whole attribute name.
+                    *i == 0 || lower.as_bytes()[i - 1].is_ascii_whitespace()
+                })
+            })
+            .min_by_key(|(i, _)| *i);
+        let (start, attr) = match found {
+            Some(x) => x,
+            None => break,
+        };
+        let value_start = start + attr.len();
+        out.push_str(&rest[..value_start]);
+        rest = &rest[value_start..];
+        let quote = match rest.chars().next() {
+            Some(q) if q == '"' || q == '\'' => q,
+            _ => continue,
+        };
+        let end = match rest[1..].find(quote) {
+            Some(x) => x + 1,
+            None => continue,
+        };
+        let value = &rest[1..end];
+        // Keep any query or fragment as is.
+        let split = value.find(['?', '#']).unwrap_or(value.len());
+        let (path, suffix) = value.split_at(split);
+        let replacement =
+            resolve(dir, path)
+                .and_then(|name| assets.get(&name))
+                .map(|fingerprinted| {
+                    let file = fingerprinted.rsplit('/').next().unwrap_or(fingerprinted);
+                    match path.rfind('/') {
+                        Some(i) => format!("{}{}", &path[..=i], file),
+                        None => file.to_string(),
+                    }
+                });
+        out.push(quote);
+        match replacement {
+            Some(x) => {
+                out.push_str(&x);
+                out.push_str(suffix);
+            }
+            None => out.push_str(value),
+        }
+        out.push(quote);
+        rest = &rest[end + 1..];
+    }
+    out.push_str(rest);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assets() -> AssetMap {
+        [
+            ("main.js", "main.0123abcd.js"),
+            ("css/site.css", "css/site.4567ef01.css"),
+            ("LICENSE", "LICENSE.89abcdef"),
+        ]
+        .iter()
+        .map(|(a, b)| (a.to_string(), b.to_string()))
+        .collect()
+    }
+
+    #[test]
+    fn fingerprinted_names_go_before_the_extension() {
+        assert_eq!(fingerprinted_name("main.js", "abc"), "main.abc.js");
+        assert_eq!(
+            fingerprinted_name("a/main.min.js", "abc"),
+            "a/main.abc.min.js"
+        );
+        assert_eq!(fingerprinted_name("v1.2/app", "abc"), "v1.2/app.abc");
+        assert_eq!(fingerprinted_name("LICENSE", "abc"), "LICENSE.abc");
+        assert_eq!(fingerprinted_name(".htaccess", "abc"), ".htaccess.abc");
+        assert_eq!(
+            fingerprinted_name(".well-known/security.txt", "abc"),
+            ".well-known/security.abc.txt"
+        );
+    }
+
+    #[test]
+    fn references_resolve_relative_to_the_page() {
+        assert_eq!(resolve("", "main.js").as_deref(), Some("main.js"));
+        assert_eq!(resolve("a/b", "main.js").as_deref(), Some("a/b/main.js"));
+        assert_eq!(resolve("a/b", "/main.js").as_deref(), Some("main.js"));
+        assert_eq!(
+            resolve("a/b", "./c/main.js").as_deref(),
+            Some("a/b/c/main.js")
+        );
+        assert_eq!(resolve("a/b", "../main.js").as_deref(), Some("a/main.js"));
+        assert_eq!(resolve("a/b", "../../main.js").as_deref(), Some("main.js"));
+        // Past the top of the output.
+        assert_eq!(resolve("a", "../../main.js"), None);
+        assert_eq!(resolve("", "../main.js"), None);
+    }
+
+    #[test]
+    fn absolute_urls_are_left_alone() {
+        assert_eq!(resolve("", "https://example.com/main.js"), None);
+        assert_eq!(resolve("", "//example.com/main.js"), None);
+        assert_eq!(resolve("", "data:text/javascript,1"), None);
+        assert_eq!(resolve("", "mailto:someone@example.com"), None);
+        let html = r#"<script src="https://example.com/main.js"></script><a href="//example.com/main.js">"#;
+        assert_eq!(rewrite_html(html, "", &assets()), html);
+    }
+
+    #[test]
+    fn html_points_at_fingerprinted_names() {
+        let html = r#"<link rel="stylesheet" href="css/site.css"><script src='main.js'></script>"#;
+        assert_eq!(
+            rewrite_html(html, "", &assets()),
+            r#"<link rel="stylesheet" href="css/site.4567ef01.css"><script src='main.0123abcd.js'></script>"#
+        );
+        // The way it was written is kept, just with the new file name.
+        assert_eq!(
+            rewrite_html(r#"<script SRC="../main.js"></script>"#, "pages", &assets()),
+            r#"<script SRC="../main.0123abcd.js"></script>"#
+        );
+        assert_eq!(
+            rewrite_html(r#"<script src="/main.js"></script>"#, "pages", &assets()),
+            r#"<script src="/main.0123abcd.js"></script>"#
+        );
+        assert_eq!(
+            rewrite_html(r#"<a href="LICENSE">"#, "", &assets()),
+            r#"<a href="LICENSE.89abcdef">"#
+        );
+    }
+
+    #[test]
+    fn queries_and_fragments_are_kept() {
+        assert_eq!(
+            rewrite_html(r#"<script src="main.js?v=2"></script>"#, "", &assets()),
+            r#"<script src="main.0123abcd.js?v=2"></script>"#
+        );
+        assert_eq!(
+            rewrite_html(r#"<a href="css/site.css#top">"#, "", &assets()),
+            r#"<a href="css/site.4567ef01.css#top">"#
+        );
+        // Just a fragment is the page itself.
+        assert_eq!(
+            rewrite_html(r##"<a href="#top">"##, "", &assets()),
+            r##"<a href="#top">"##
+        );
+    }
+
+    #[test]
+    fn other_attributes_and_unknown_files_are_left_alone() {
+        let html = r#"<img data-src="main.js" src="missing.png"><p>src="main.js"</p>"#;
+        assert_eq!(rewrite_html(html, "", &assets()), html);
+    }
+
+    #[test]
+    fn copies_are_not_fingerprinted_again() {
+        let output = ::tempfile::tempdir().unwrap();
+        fs::create_dir(output.path().join("img")).unwrap();
+        fs::write(output.path().join("main.js"), "main").unwrap();
+        fs::write(output.path().join("img/logo.png"), "logo").unwrap();
+        fs::write(
+            output.path().join("index.html"),
+            r#"<script src="main.js">"#,
+        )
+        .unwrap();
+        let first = fingerprint(output.path()).unwrap();
+        assert_eq!(
+            first.keys().collect::<Vec<_>>(),
+            vec!["img/logo.png", "main.js"]
+        );
+        // The second round sees the first round's copies, and skips them.
+        let second = fingerprint(output.path()).unwrap();
+        assert_eq!(first, second);
+        let mut files: Vec<_> = WalkDir::new(output.path())
+            .into_iter()
+            .map(|x| x.unwrap())
+            .filter(|x| x.file_type().is_file())
+            .map(|x| logical_name(output.path(), x.path()))
+            .collect();
+        files.sort();
+        let mut expected = vec![
+            ASSET_MANIFEST.to_string(),
+            "index.html".into(),
+            "main.js".into(),
+            "img/logo.png".into(),
+        ];
+        expected.extend(first.values().cloned());
+        expected.sort();
+        assert_eq!(files, expected);
+    }
+}
@@ -0,0 +1,171 @@
+//! Saying what's going on, and who's saying it.
+//! Most commands run one thing at a time, so their output is printed plainly.
+//! In dev mode, the watcher, the browser sessions, the tools and the server
+//! all talk at once, so every line gets a timestamp and a colored label
+//! for where it came from. Children's output goes through here too, line by line.
+//!
+//! Debug messages only show up with `--verbose`.
+use ::std::fmt;
+use ::std::io::{self, IsTerminal, Write};
+use ::std::sync::atomic::{AtomicBool, Ordering};
+use ::std::sync::Mutex;
+use ::std::time::{SystemTime, UNIX_EPOCH};
+
+static VERBOSE: AtomicBool = AtomicBool::new(false);
+static PREFIXED: AtomicBool = AtomicBool::new(false);
+static COLOR: AtomicBool = AtomicBool::new(false);
+/// So lines from different threads don't get mixed up.
+static LOCK: Mutex<()> = Mutex::new(());
+
+/// How wide labels are padded to, so messages line up.
+const LABEL_WIDTH: usize = 7;
+/// Colors for labels, picked by name, so each source keeps its color.
+const COLORS: &[&str] = &["36", "35", "34", "33", "32", "96", "95", "94", "93", "92"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum Level {
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+/// Set up logging. Call this once, before anything gets logged.
+/// `prefixed` is for when lots of things are talking at once.
+pub(crate) fn init(verbose: bool, prefixed: bool) {
+    VERBOSE.store(verbose, Ordering::Relaxed);
+    PREFIXED.store(prefixed, Ordering::Relaxed);
+    let color = ::std::env::var_os("NO_COLOR").is_none() && io::stdout().is_terminal();
+    COLOR.store(color, Ordering::Relaxed);
+}
+
+/// Wall clock time, like `14:03:27.512`.
+pub(crate) fn clock(time: SystemTime) -> String {
+    let now = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let (hours, minutes, seconds) = time_of_day(now.as_secs());
+    format!(
+        "{:02}:{:02}:{:02}.{:03}",
+        hours,
+        minutes,
+        seconds,
+        now.subsec_millis()
+    )
+}
+
+/// Local time.
+#[cfg(unix)]
+fn time_of_day(secs: u64) -> (u32, u32, u32) {
+    let secs = secs as ::libc::time_t;
+    // Safe, since `localtime_r` only writes to the `tm` we give it.
+    let tm = unsafe {
+        let mut tm = ::std::mem::zeroed::<::libc::tm>();
+        ::libc::localtime_r(&secs, &mut tm);
+        tm
+    };
+    (tm.tm_hour as u32, tm.tm_min as u32, tm.tm_sec as u32)
+}
+
+/// UTC, since there's no `localtime_r`.
+#[cfg(not(unix))]
+fn time_of_day(secs: u64) -> (u32, u32, u32) {
+    let secs = secs % (24 * 60 * 60);
+    (
+        (secs / 3600) as u32,
+        (secs / 60 % 60) as u32,
+        (secs % 60) as u32,
+    )
+}
+
+fn label_color(source: &str) -> &'static str {
+    let hash = source
+        .bytes()
+        .fold(0usize, |h, b| h.wrapping_mul(31).wrapping_add(b as usize));
+    COLORS[hash % COLORS.len()]
+}
+
+/// Write `text` from `source`, a line at a time.
+/// `tag` goes before the first line, like `warning: `.
+fn write(source: &str, level: Level, to_stderr: bool, tag: &str, text: &str) {
+    let _lock = LOCK.lock().unwrap_or_else(|x| x.into_inner());
+    let mut out: Box<dyn Write> = if to_stderr {
+        Box::new(io::stderr().lock())
+    } else {
+        Box::new(io::stdout().lock())
+    };
+    if !PREFIXED.load(Ordering::Relaxed) {
+        let _ = writeln!(out, "{}{}", tag, text);
+        return;
+    }
+    let color = COLOR.load(Ordering::Relaxed);
+    let time = clock(SystemTime::now());
+    let label = format!("{:>width$}", source, width = LABEL_WIDTH);
+    let (tag_color, reset) = match level {
+        _ if !color => ("", ""),
+        Level::Error => ("\x1b[31m", "\x1b[0m"),
+        Level::Warn => ("\x1b[33m", "\x1b[0m"),
+        Level::Debug => ("\x1b[2m", "\x1b[0m"),
+        Level::Info => ("", ""),
+    };
+    let mut first = true;
+    // An empty message is still a line.
+    for line in text.split('\n') {
+        let tag = if first { tag } else { "" };
+        first = false;
+        let _ = if color {
+            writeln!(
+                out,
+                "\x1b[2m{}\x1b[0m \x1b[{}m{}\x1b[0m | {}{}{}{}",
+                time,
+                label_color(source),
+                label,
+                tag_color,
+                tag,
+                line,
+                reset
+            )
+        } else {
+            writeln!(out, "{} {} | {}{}", time, label, tag, line)
+        };
+    }
+}
+
+/// Log a message. Use the macros instead.
+pub(crate) fn log(source: &str, level: Level, args: fmt::Arguments) {
+    if level == Level::Debug && !VERBOSE.load(Ordering::Relaxed) {
+        return;
+    }
+    let tag = match level {
+        Level::Warn => "warning: ",
+        Level::Error => "error: ",
+        Level::Debug | Level::Info => "",
+    };
+    write(source, level, level >= Level::Warn, tag, &args.to_string());
+}
+
+/// A line some other program printed.
+/// It's passed on as it is, apart from the prefix.
+pub(crate) fn child_line(source: &str, stderr: bool, line: &str) {
+    write(source, Level::Info, stderr, "", line.trim_end_matches('\r'));
+}
+
+macro_rules! debug {
+    ($source:expr, $($arg:tt)+) => {
+        $crate::log::log($source, $crate::log::Level::Debug, format_args!($($arg)+))
+    };
+}
+macro_rules! info {
+    ($source:expr, $($arg:tt)+) => {
+        $crate::log::log($source, $crate::log::Level::Info, format_args!($($arg)+))
+    };
+}
+macro_rules! warning {
+    ($source:expr, $($arg:tt)+) => {
+        $crate::log::log($source, $crate::log::Level::Warn, format_args!($($arg)+))
+    };
+}
+macro_rules! error {
+    ($source:expr, $($arg:tt)+) => {
+        $crate::log::log($source, $crate::log::Level::Error, format_args!($($arg)+))
+    };
+}
+pub(crate) use {debug, error, info, warning};
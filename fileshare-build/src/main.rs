@@ -1,17 +1,50 @@
 //! Doing build logic in shell scripts is lame.
 //! Let's just use Rust.
-use ::globset::{Glob, GlobSetBuilder};
+use ::std::collections::BTreeMap;
 use ::std::fs;
 use ::std::path::{Path, PathBuf};
-use ::std::process::{self, Command, Stdio};
+use ::std::process::{self, Command};
+use ::std::thread;
 use ::structopt::StructOpt;
-use ::walkdir::WalkDir;
 
+mod check;
+mod clean;
+mod compress;
+mod config;
+mod doc;
+mod e2e;
+mod embed;
+mod faults;
+mod fingerprint;
+mod log;
+mod manifest;
+mod minify;
+mod package;
+mod protocol;
+mod rules;
+mod runner;
 mod server;
+mod status;
+mod supervisor;
+mod testing;
+mod toolchain;
+mod watch;
+
+use config::Config;
+use manifest::{Hasher, Manifest};
 
 #[derive(Debug, StructOpt, Clone)]
 struct Opt {
     project_root: PathBuf,
+    /// Use this config file instead of the project's `fileshare-build.toml`
+    #[structopt(long)]
+    config: Option<PathBuf>,
+    /// Put the built site here, instead of where the config says
+    #[structopt(long)]
+    output_dir: Option<PathBuf>,
+    /// Say more about what's going on, like every file change noticed
+    #[structopt(long, short)]
+    verbose: bool,
     #[structopt(subcommand)]
     target: Target,
 }
@@ -23,15 +56,79 @@ enum Target {
         /// Build artifacts in release mode, with optimizations
         #[structopt(long, short)]
         release: bool,
+        /// Skip minifying the Elm output, whatever the config says
+        #[structopt(long)]
+        no_minify: bool,
+        /// Rebuild everything, even what looks up to date
+        #[structopt(long)]
+        force: bool,
+        /// Bake the built site into the server binary
+        #[structopt(long)]
+        embed: bool,
     },
     /// Builds the whole app
     Build {
         /// Build artifacts in release mode, with optimizations
         #[structopt(long, short)]
         release: bool,
+        /// Skip minifying the Elm output, whatever the config says
+        #[structopt(long)]
+        no_minify: bool,
+        /// Rebuild everything, even what looks up to date
+        #[structopt(long)]
+        force: bool,
+        /// Bake the built site into the server binary
+        #[structopt(long)]
+        embed: bool,
+        /// Keep rebuilding the site as it changes, without building or running the server
+        #[structopt(long, conflicts_with_all = &["release", "embed"])]
+        watch: bool,
+    },
+    /// Does a release build, and bundles it up for deploying
+    Package {
+        /// Skip minifying the Elm output, whatever the config says
+        #[structopt(long)]
+        no_minify: bool,
+        /// Rebuild everything, even what looks up to date
+        #[structopt(long)]
+        force: bool,
+        /// Bake the built site into the server binary
+        #[structopt(long)]
+        embed: bool,
+    },
+    /// Type checks and lints everything, without building anything
+    Check {
+        /// How to print problems: `human`, or `json`, one object per line
+        #[structopt(long, default_value = "human", possible_values = &["human", "json"])]
+        message_format: String,
+    },
+    /// Builds the Rust and Elm docs, with an index for both
+    Doc,
+    /// Runs the Rust, Elm and end to end tests
+    Test {
+        /// Also write the results here, as JUnit XML
+        #[structopt(long)]
+        junit: Option<PathBuf>,
+        /// Skip the end to end tests, which need a full build
+        #[structopt(long)]
+        no_e2e: bool,
+        /// Run the end to end tests through the fault injection proxy
+        #[structopt(long, conflicts_with = "no-e2e")]
+        faults: bool,
     },
     /// Starts the app in full on live reloading dev mode
-    Dev,
+    Dev {
+        /// Port for the live reload server
+        #[structopt(long)]
+        port: Option<u16>,
+        /// Address for the live reload server
+        #[structopt(long)]
+        address: Option<String>,
+        /// Put a proxy in front of the server that makes the network bad,
+        /// as set up in `[dev.faults]`
+        #[structopt(long)]
+        faults: bool,
+    },
     /// Remove build artifacts
     Clean {
         /// Whether to narrow cleaning to the site directory
@@ -46,9 +143,50 @@ enum Target {
         /// Whether to narrow cleaning to documentation
         #[structopt(long)]
         doc: bool,
+        /// Whether to narrow cleaning to the build manifest,
+        /// so the next build redoes everything,
+        /// and the fingerprinted copies of the site's assets
+        #[structopt(long)]
+        fingerprints: bool,
+        /// Whether to narrow cleaning to the pinned tools we downloaded.
+        /// Never cleaned unless asked for, since it's shared between projects
+        #[structopt(long)]
+        toolchain: bool,
+        /// Only say what would be removed, and how big it is
+        #[structopt(long)]
+        dry_run: bool,
     },
 }
 
+impl Opt {
+    /// Command line flags beat the config file.
+    fn configure(&self, config: &mut config::Config) {
+        if let Some(ref dir) = self.output_dir {
+            config.build.output_dir = dir.clone();
+        }
+        match self.target {
+            Target::Run { no_minify, .. }
+            | Target::Build { no_minify, .. }
+            | Target::Package { no_minify, .. } => {
+                if no_minify {
+                    config.minify.enabled = false;
+                }
+            }
+            Target::Dev {
+                port, ref address, ..
+            } => {
+                if let Some(port) = port {
+                    config.dev.port = port;
+                }
+                if let Some(address) = address {
+                    config.dev.address = address.clone();
+                }
+            }
+            Target::Check { .. } | Target::Doc | Target::Test { .. } | Target::Clean { .. } => (),
+        }
+    }
+}
+
 /// A little abstraction because I messed this up before, lol.
 struct NormalizedClean {
     // These are normalized.
@@ -56,149 +194,441 @@ struct NormalizedClean {
     html: bool,
     elm: bool,
     rust: bool,
+    fingerprints: bool,
+    toolchain: bool,
     // This one is special,
     // in that it alters the others.
     doc: bool,
 }
 impl NormalizedClean {
     /// Normalize from narrowing form.
-    fn new(html: bool, elm: bool, rust: bool, doc: bool) -> Self {
-        match (html, elm, rust) {
-            (false, false, false) => Self {
+    /// With nothing narrowed, everything but the toolchain goes,
+    /// since that's a pain to get back.
+    fn new(
+        html: bool,
+        elm: bool,
+        rust: bool,
+        fingerprints: bool,
+        toolchain: bool,
+        doc: bool,
+    ) -> Self {
+        match (html, elm, rust, fingerprints, toolchain) {
+            (false, false, false, false, false) => Self {
                 html: true,
                 elm: true,
                 rust: true,
+                fingerprints: true,
+                toolchain: false,
                 doc,
             },
-            (html, elm, rust) => Self {
+            (html, elm, rust, fingerprints, toolchain) => Self {
                 html,
                 elm,
                 rust,
+                fingerprints,
+                toolchain,
                 doc,
             },
         }
     }
 }
 
-fn cargo(project_root: &Path, release: bool, subcommand: &str) -> anyhow::Result<()> {
+/// Remove whatever `normal` says to.
+/// Rust goes last, since `cargo clean` takes out everything under `target/`.
+fn clean(
+    project_root: &Path,
+    config: &Config,
+    normal: &NormalizedClean,
+    dry_run: bool,
+) -> anyhow::Result<()> {
+    let mut cleaner = clean::Cleaner::new(dry_run);
+    if normal.html {
+        if normal.doc {
+            // There are no docs for this target, yet.
+        } else {
+            cleaner.remove("html", &config.build.output_dir)?;
+        }
+    }
+    if normal.elm {
+        if normal.doc {
+            cleaner.remove("elm docs", &project_root.join(doc::ELM_DOC_DIR))?;
+        } else {
+            cleaner.remove("elm", &config.elm.elm_stuff())?;
+        }
+    }
+    // None of these have docs.
+    if !normal.doc {
+        if normal.fingerprints {
+            cleaner.remove("fingerprints", &project_root.join(manifest::MANIFEST_PATH))?;
+            // Cleaning the html takes these with it.
+            if !normal.html {
+                for file in fingerprint::fingerprinted_files(&config.build.output_dir) {
+                    cleaner.remove("fingerprints", &file)?;
+                }
+            }
+        }
+        if normal.toolchain {
+            let toolchain = toolchain::Toolchain::new(&config.toolchain)?;
+            cleaner.remove("toolchain", toolchain.cache_dir())?;
+        }
+    }
+    if normal.rust {
+        cleaner.cargo(project_root, normal.doc)?;
+    }
+    println!("{}", cleaner.summary());
+    Ok(())
+}
+
+/// How `cargo build` is asked to say what it built.
+/// Diagnostics still come out on stderr, the way people are used to.
+const ARTIFACT_FORMAT: &str = "--message-format=json-render-diagnostics";
+
+fn cargo(project_root: &Path, release: bool, embed: bool, subcommand: &str) -> anyhow::Result<()> {
+    let mut c = cargo_command(project_root, release, embed, subcommand);
+    runner::run(
+        &format!("cargo {}", subcommand),
+        &mut c,
+        OutputMethod::Forward,
+    )?;
+
+    Ok(())
+}
+
+/// Becoming the server, rather than waiting on it,
+/// means signals go straight to it, and its exit code is ours.
+/// Only returns if it couldn't.
+#[cfg(unix)]
+fn become_server(server: &Path, project_root: &Path) -> anyhow::Error {
+    use ::std::os::unix::process::CommandExt;
+    let e = Command::new(server).current_dir(project_root).exec();
+    anyhow::Error::new(e).context(format!("couldn't run {}", server.display()))
+}
+
+/// Elsewhere, it's just run, and its exit code passed on.
+#[cfg(not(unix))]
+fn become_server(server: &Path, project_root: &Path) -> anyhow::Error {
+    match Command::new(server).current_dir(project_root).status() {
+        Ok(status) => process::exit(status.code().unwrap_or(1)),
+        Err(e) => anyhow::Error::new(e).context(format!("couldn't run {}", server.display())),
+    }
+}
+
+/// `cargo build` the app, and return where its executable ended up.
+/// Cargo says, so this doesn't guess at what's in `target/`.
+fn build_app(project_root: &Path, release: bool, embed: bool) -> anyhow::Result<PathBuf> {
+    let name = package::app_package(project_root)?.name;
+    let mut c = cargo_command(project_root, release, embed, "build");
+    c.arg(ARTIFACT_FORMAT);
+    let output = runner::run("cargo build", &mut c, OutputMethod::ForwardStderr)?;
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .rev()
+        .find_map(|x| artifact_executable(x, &name))
+        .ok_or_else(|| anyhow::anyhow!("cargo didn't say where it built {}", name))
+}
+
+/// The executable in one of `cargo`'s JSON messages, if it's `name`'s.
+fn artifact_executable(message: &str, name: &str) -> Option<PathBuf> {
+    let message: ::serde_json::Value = ::serde_json::from_str(message).ok()?;
+    if message["reason"] != "compiler-artifact" || message["target"]["name"] != name {
+        return None;
+    }
+    message["executable"].as_str().map(PathBuf::from)
+}
+
+fn cargo_command(project_root: &Path, release: bool, embed: bool, subcommand: &str) -> Command {
     let mut c = Command::new("cargo");
     c.arg(subcommand);
     if release {
         c.arg("--release");
     }
+    if embed {
+        c.arg("--features")
+            .arg(embed::EMBED_FEATURE)
+            .env(embed::EMBED_ENV, project_root.join(embed::EMBED_PATH));
+    }
     c.arg("--manifest-path")
-        .arg(project_root.join("Cargo.toml"))
-        .spawn()?
-        .wait()
-        .expect("failed to wait on child");
-
-    Ok(())
-}
-
-/// Run some commands and pipe between them
-macro_rules! sp {
-    // No pipe case
-    ($cmd:expr ; $($arg:expr),*) => {
-        Command::new($cmd) $(.arg($arg))* .spawn()?.wait().expect("failed to wait on child");
-    };
-    // Match front of invocation
-    ($cmd:expr ; $($arg:expr),* => $($t:tt)*) => {
-        let mut c = Command::new($cmd) $(.arg($arg))* .stdout(Stdio::piped()) .spawn()?;
-        if let Some(c_out) = c.stdout.take() {
-            // Next command
-            sp! { (c_out) -> $($t)* }
-        }
-    };
-    // Inner invocation
-    (($pre:expr) -> $cmd:expr ; $($arg:expr),* => $($t:tt)* ) => {
-        let mut c = Command::new($cmd) $(.arg($arg))* .stdin($pre) .stdout(Stdio::piped()) .spawn()?;
-        if let Some(c_out) = c.stdout.take() {
-            // Next command
-            sp! { (c_out) -> $($t)* }
-        }
-    };
-    // Final invocation
-    (($pre:expr) -> $cmd:expr ; $($arg:expr),*) => {
-        Command::new($cmd) $(.arg($arg))* .stdin($pre).spawn()?.wait().expect("failed to wait on child");
-    };
+        .arg(project_root.join("Cargo.toml"));
+    c
 }
 
 pub(crate) enum OutputMethod {
+    /// Pass stdout and stderr through to ours.
     Forward,
+    /// Pass stderr through, but keep stdout for us.
+    ForwardStderr,
     Capture,
 }
 
-fn elm(
+/// An Elm program that didn't build, and what `elm make` had to say about it.
+pub(crate) struct ElmFailure {
+    pub(crate) entrypoint: PathBuf,
+    pub(crate) output: process::Output,
+}
+impl ElmFailure {
+    /// The compiler's complaint, labelled with the program it's about.
+    pub(crate) fn report(&self) -> String {
+        format!(
+            "-- {} --\n{}{}",
+            self.entrypoint.display(),
+            String::from_utf8_lossy(&self.output.stdout),
+            String::from_utf8_lossy(&self.output.stderr)
+        )
+    }
+}
+
+/// Just enough of `elm.json` to know where the sources are.
+#[derive(::serde::Deserialize)]
+struct ElmJson {
+    #[serde(rename = "source-directories", default)]
+    source_directories: Vec<PathBuf>,
+}
+
+/// Where the Elm sources live, going by `elm.json`.
+pub(crate) fn elm_source_dirs(config: &Config) -> anyhow::Result<Vec<PathBuf>> {
+    let elm_json = config.elm.project_dir.join("elm.json");
+    let mut dirs = ::serde_json::from_slice::<ElmJson>(&fs::read(&elm_json)?)?.source_directories;
+    if dirs.is_empty() {
+        dirs.push("src".into());
+    }
+    Ok(dirs
+        .into_iter()
+        .map(|x| config.elm.project_dir.join(x))
+        .collect())
+}
+
+/// Hash everything every Elm entrypoint depends on:
+/// all the Elm sources, `elm.json`, the tools, and how we're using them.
+fn elm_inputs(
     project_root: &Path,
+    config: &Config,
+    rules: &rules::Rules,
+    elm: &Path,
+    minifier: Option<minify::Minifier>,
+    release: bool,
+) -> anyhow::Result<Hasher> {
+    let mut hasher = Hasher::new(project_root);
+    hasher
+        .str(&manifest::tool_version(elm))
+        .str(if release { "release" } else { "debug" });
+    match minifier {
+        Some(minifier) => {
+            hasher
+                .str(&minifier.version())
+                .str(&format!("{:?}", config.minify));
+        }
+        None => {
+            hasher.str("unminified");
+        }
+    }
+    hasher.file(&config.elm.project_dir.join("elm.json"))?;
+    let mut sources = Vec::new();
+    for dir in elm_source_dirs(config)? {
+        sources.extend(
+            rules
+                .walk(&dir)
+                .filter(|(_, kind)| *kind == rules::Kind::Elm)
+                .map(|(path, _)| path),
+        );
+    }
+    sources.sort();
+    for source in sources {
+        hasher.file(&source)?;
+    }
+    Ok(hasher)
+}
+
+/// Build every Elm entrypoint, all at once.
+/// `elm make` takes a lock on `elm-stuff`, so the compiles themselves
+/// mostly take turns, but the minifying doesn't have to.
+/// One broken program doesn't stop the others from building.
+/// Entrypoints the manifest says are up to date are skipped.
+fn elm(
+    config: &Config,
+    rules: &rules::Rules,
+    manifest: &mut Manifest,
     elm: &Path,
     terser: Option<&Path>,
     release: bool,
     out: OutputMethod,
-) -> anyhow::Result<Option<process::Output>> {
-    let mut c = Command::new(elm);
-    c.arg("make");
-    if release {
-        c.arg("--optimize");
+) -> anyhow::Result<Vec<ElmFailure>> {
+    let minifier = minify::choose(&config.minify, terser)?;
+    let base = elm_inputs(manifest.root(), config, rules, elm, minifier, release)?;
+    let mut stale = Vec::new();
+    for entry in &config.elm.entrypoints {
+        let output = config.build.output_dir.join(&entry.output);
+        let key = format!("elm:{}", manifest::relative(manifest.root(), &output));
+        let inputs = base.clone().path(&entry.source).path(&output).finish();
+        if manifest.is_fresh(&key, &inputs) {
+            if let OutputMethod::Forward = out {
+                log::info!("elm", "-- {} -- up to date", entry.source.display());
+            }
+        } else {
+            // Don't let a map from some earlier build outlive its bundle.
+            let _ = fs::remove_file(minify::source_map_path(&output));
+            stale.push((entry, output, key, inputs));
+        }
     }
-    c.arg(project_root.join("src/Main.elm"))
-        .arg("--output")
-        .arg(project_root.join("static/main.js"));
-    match out {
-        OutputMethod::Forward => {
-            c.spawn()?.wait().expect("failed to wait on child");
+    let results = thread::scope(|s| {
+        let handles = stale
+            .iter()
+            .map(|(entry, ..)| {
+                s.spawn(move || elm_entrypoint(config, entry, elm, minifier, release))
+            })
+            .collect::<Vec<_>>();
+        handles
+            .into_iter()
+            .map(|h| h.join().expect("Elm build thread panicked"))
+            .collect::<Vec<_>>()
+    });
+    let mut failures = Vec::new();
+    for ((entry, output_path, key, inputs), result) in stale.into_iter().zip(results) {
+        // Whatever happens, the old record is no good anymore.
+        manifest.invalidate(&key);
+        let output = result?;
+        if let OutputMethod::Forward = out {
+            // Everything ran at once, so we held onto the output
+            // until now to keep it from getting jumbled up.
+            use ::std::io::Write;
+            let mut stdout = ::std::io::stdout();
+            writeln!(stdout, "-- {} --", entry.source.display())?;
+            stdout.write_all(&output.stdout)?;
+            stdout.write_all(&output.stderr)?;
+            stdout.flush()?;
         }
-        OutputMethod::Capture => {
-            let output = c.output().expect("failed to wait on child");
-            if !output.status.success() {
-                return Ok(Some(output));
+        if output.status.success() {
+            let map = minify::source_map_path(&output_path);
+            if map.is_file() {
+                manifest.record(&key, inputs, &[&output_path, &map])?;
+            } else {
+                manifest.record(&key, inputs, &[&output_path])?;
             }
+        } else {
+            failures.push(ElmFailure {
+                entrypoint: entry.source.clone(),
+                output,
+            });
         }
     }
-    // We don't collect the output for this,
-    // since its success or failure is entirely
-    // contingent on the success or failure of `elm make`.
-    if let Some(terser) = terser {
-        sp! { terser ; project_root.join("static/main.js"), "--compress",
-              "pure_funcs=\"F2,F3,F4,F5,F6,F7,F8,F9,A2,A3,A4,A5,A6,A7,A8,A9\",pure_getters,keep_fargs=false,unsafe_comps,unsafe"
-              => terser ; "--mangle", "--output", project_root.join("static").join("main.js")
-        }
+
+    Ok(failures)
+}
+
+fn elm_entrypoint(
+    config: &Config,
+    entry: &config::Entrypoint,
+    elm: &Path,
+    minifier: Option<minify::Minifier>,
+    release: bool,
+) -> anyhow::Result<process::Output> {
+    let output = config.build.output_dir.join(&entry.output);
+    let mut c = Command::new(elm);
+    c.current_dir(&config.elm.project_dir).arg("make");
+    if release {
+        c.arg("--optimize");
+    }
+    c.arg(&entry.source).arg("--output").arg(&output);
+    let step = format!("elm make {}", entry.source.display());
+    let result = match runner::run(&step, &mut c, OutputMethod::Capture) {
+        Ok(x) => x,
+        // A compile error is a normal thing to happen,
+        // so we hand it back for the caller to show off.
+        Err(runner::StepError::Failed { output, .. }) => return Ok(output),
+        Err(e) => return Err(e.into()),
+    };
+    if let Some(minifier) = minifier {
+        minify::minify(&config.minify, minifier, &output)?;
     }
 
-    Ok(None)
+    Ok(result)
 }
 
 /// Any source files we just need to copy into the output.
-/// This currently means `.html` and `.css` files.
-pub(crate) fn copy(project_root: &Path) -> anyhow::Result<()> {
-    let mut builder = GlobSetBuilder::new();
-    builder.add(Glob::new("*.html")?);
-    builder.add(Glob::new("*.css")?);
-    builder.add(Glob::new("*.js")?);
-    let matcher = builder.build()?;
-    let ignore_matcher = Glob::new("*#*")?.compile_matcher();
-    for entry in WalkDir::new(project_root.join("src")) {
-        let entry = entry?;
-        if matcher.is_match(entry.path()) && !ignore_matcher.is_match(entry.path()) {
-            let depth = entry.depth();
-            let rel = entry
-                .path()
-                .components()
-                .rev()
-                .take(depth)
-                .collect::<Vec<_>>()
-                .into_iter()
-                .rev()
-                .collect::<PathBuf>();
-            let dest = project_root.join("static").join(&rel);
-            println!("src: {:?}, dest: {:?}", rel, dest);
+/// What counts is up to the [`Rules`](rules::Rules).
+/// Files already copied, and unchanged since, are left alone.
+pub(crate) fn copy(
+    config: &Config,
+    rules: &rules::Rules,
+    manifest: &mut Manifest,
+) -> anyhow::Result<()> {
+    let src = &config.build.source_dir;
+    for (path, kind) in rules.walk(src) {
+        if kind == rules::Kind::Copy {
+            let rel = path.strip_prefix(src)?;
+            let dest = config.build.output_dir.join(rel);
+            let key = format!("copy:{}", manifest::relative(manifest.root(), &dest));
+            let inputs = Hasher::new(manifest.root()).file(&path)?.finish();
+            if manifest.is_fresh(&key, &inputs) {
+                continue;
+            }
+            log::debug!("copy", "src: {:?}, dest: {:?}", rel, dest);
             ::fsio::file::ensure_exists(&dest).map_err(|e| ::anyhow::anyhow!(e))?;
-            fs::copy(entry.path(), &dest)?;
+            fs::copy(&path, &dest)?;
+            manifest.record(&key, inputs, &[&dest])?;
         }
     }
 
     Ok(())
 }
 
+/// Everything but the Rust.
+/// Any Elm program failing to build fails the whole thing,
+/// so we don't go on to run a server with a stale bundle.
+fn build_site(
+    config: &Config,
+    rules: &rules::Rules,
+    manifest: &mut Manifest,
+    bins: &Binaries,
+    release: bool,
+) -> anyhow::Result<()> {
+    manifest.set_tools(bins.versions.clone());
+    copy(config, rules, manifest)?;
+    // There's no dev server for `reload.js` to talk to.
+    protocol::write_reload_config(&config.build.output_dir, None)?;
+    let failures = elm(
+        config,
+        rules,
+        manifest,
+        &bins.elm,
+        bins.terser.as_deref(),
+        release,
+        OutputMethod::Forward,
+    );
+    // Save whatever did work, even if something didn't.
+    manifest.save()?;
+    let failures = failures?;
+    if !failures.is_empty() {
+        let names = failures
+            .iter()
+            .map(|x| x.entrypoint.display().to_string())
+            .collect::<Vec<_>>();
+        ::anyhow::bail!("Elm build failed for {}", names.join(", "));
+    }
+    if release {
+        let assets = fingerprint::fingerprint(&config.build.output_dir)?;
+        println!("fingerprinted {} assets", assets.len());
+        let compressed = compress::precompress(&config.build.output_dir, manifest);
+        manifest.save()?;
+        println!("precompressed {} assets", compressed?);
+    } else {
+        let removed = compress::remove_precompressed(&config.build.output_dir, manifest);
+        manifest.save()?;
+        let removed = removed?;
+        if removed > 0 {
+            println!("removed {} precompressed assets", removed);
+        }
+    }
+    Ok(())
+}
+
+/// Write the code that bakes the site into the server,
+/// for `cargo` to pick up with `embed` set.
+fn embed_site(project_root: &Path, config: &Config, manifest: &Manifest) -> anyhow::Result<()> {
+    let embedded = embed::generate(project_root, &config.build.output_dir, manifest)?;
+    println!("embedded {} assets", embedded);
+    Ok(())
+}
+
 /// A collection of the binaries we need to
 /// build the whole app.
 /// Useful binaries we can go without
@@ -207,92 +637,184 @@ struct Binaries {
     /// The [Elm](https://elm-lang.org) compiler!
     elm: PathBuf,
     terser: Option<PathBuf>,
+    /// Exactly which version of each tool we're using, for the build manifest.
+    versions: BTreeMap<String, String>,
 }
 impl Binaries {
     /// Find, build, or otherwise obtain the binaries we
     /// need to build the whole app.
+    /// Pinned tools come from the toolchain cache,
+    /// and everything else from `PATH`.
     /// This does not include `rustc` or `cargo`.
-    fn collect() -> Result<Binaries, anyhow::Error> {
+    fn collect(config: &Config) -> Result<Binaries, anyhow::Error> {
         use ::which::which;
-        let bins = Self {
-            elm: match which("elm") {
-                Ok(x) => x,
-                Err(_) => ::anyhow::bail!("we don't handle not having Elm yet"),
-            },
-            terser: match which("terser") {
-                Ok(x) => Some(x),
-                // We don't handle not having Terser yet, but it's not fatal.
-                // TODO: Fetch Terser.
-                Err(_) => None,
-            },
+        let pins = &config.toolchain;
+        let toolchain = toolchain::Toolchain::new(pins)?;
+        let mut versions = BTreeMap::new();
+        let elm = match pins.elm {
+            Some(ref pin) => toolchain.ensure("elm", pin)?,
+            None => which("elm").map_err(|_| {
+                ::anyhow::anyhow!(
+                    "couldn't find `elm` on PATH; install it, or pin it under `[toolchain.elm]`"
+                )
+            })?,
+        };
+        let terser = match pins.terser {
+            Some(ref pin) => Some(toolchain.ensure("terser", pin)?),
+            // Not fatal; there may be a minifier built in,
+            // and if not, we just skip minifying.
+            None => which("terser").ok(),
         };
-        Ok(bins)
+        for (name, bin) in [("elm", Some(&elm)), ("terser", terser.as_ref())] {
+            if let Some(bin) = bin {
+                versions.insert(name.to_string(), manifest::tool_version(bin));
+            }
+        }
+        Ok(Self {
+            elm,
+            terser,
+            versions,
+        })
     }
 }
 
 fn main() -> ::anyhow::Result<()> {
-    let opt = Opt::from_args();
+    let mut opt = Opt::from_args();
+    // Dev mode has everything going at once, so it needs labels.
+    log::init(opt.verbose, matches!(opt.target, Target::Dev { .. }));
     // println!("Args: {:?}", opt);
-    let bins = Binaries::collect()?;
+    // The watcher reports absolute paths, so everything else had better agree.
+    opt.project_root = opt.project_root.canonicalize()?;
+    // Paths on the command line are relative to where we're run,
+    // unlike the ones in the config, which are relative to the project.
+    let cwd = ::std::env::current_dir()?;
+    opt.config = opt.config.map(|x| cwd.join(x));
+    opt.output_dir = opt.output_dir.map(|x| cwd.join(x));
+    if let Target::Test { ref mut junit, .. } = opt.target {
+        *junit = junit.take().map(|x| cwd.join(x));
+    }
+    let mut config = Config::load(&opt.project_root, opt.config.as_deref())?;
+    opt.configure(&mut config);
+    let rules = rules::Rules::new(&opt.project_root, &config.assets)?;
+    // Only what uses the tools collects them,
+    // so cleaning up doesn't need Elm, let alone downloading it.
+    let bins = || Binaries::collect(&config);
     match opt.target {
-        Target::Run { release } => {
-            copy(&opt.project_root)?;
-            elm(
-                &opt.project_root,
-                &bins.elm,
-                bins.terser.as_deref(),
-                release,
-                OutputMethod::Forward,
-            )?;
-            cargo(&opt.project_root, release, "run")?;
+        Target::Run {
+            release,
+            force,
+            embed,
+            ..
+        } => {
+            let mut manifest = Manifest::load(&opt.project_root, force);
+            build_site(&config, &rules, &mut manifest, &bins()?, release)?;
+            if embed {
+                embed_site(&opt.project_root, &config, &manifest)?;
+            }
+            let server = build_app(&opt.project_root, release, embed)?;
+            return Err(become_server(&server, &opt.project_root));
+        }
+        Target::Build {
+            force, watch: true, ..
+        } => {
+            let bins = bins()?;
+            let manifest = Manifest::load(&opt.project_root, force);
+            watch::build(config, bins, rules, manifest)?;
+        }
+        Target::Build {
+            release,
+            force,
+            embed,
+            ..
+        } => {
+            let mut manifest = Manifest::load(&opt.project_root, force);
+            build_site(&config, &rules, &mut manifest, &bins()?, release)?;
+            if embed {
+                embed_site(&opt.project_root, &config, &manifest)?;
+            }
+            cargo(&opt.project_root, release, embed, "build")?;
         }
-        Target::Build { release } => {
-            copy(&opt.project_root)?;
-            elm(
-                &opt.project_root,
-                &bins.elm,
-                bins.terser.as_deref(),
-                release,
-                OutputMethod::Forward,
-            )?;
-            cargo(&opt.project_root, release, "build")?;
+        Target::Package { force, embed, .. } => {
+            let mut manifest = Manifest::load(&opt.project_root, force);
+            build_site(&config, &rules, &mut manifest, &bins()?, true)?;
+            if embed {
+                embed_site(&opt.project_root, &config, &manifest)?;
+            }
+            let server = build_app(&opt.project_root, true, embed)?;
+            let bundle = package::package(&opt.project_root, &config, &manifest, &server, embed)?;
+            println!("packaged {}", bundle.display());
+        }
+        Target::Check { ref message_format } => {
+            let diagnostics = check::check(&opt.project_root, &config, &bins()?.elm)?;
+            if message_format == "json" {
+                check::print_json(&diagnostics)?;
+            } else {
+                check::print_human(&opt.project_root, &diagnostics);
+            }
+            let errors = diagnostics
+                .iter()
+                .filter(|x| x.severity == check::Severity::Error)
+                .count();
+            let warnings = diagnostics.len() - errors;
+            // Keep stdout clean for whatever's reading the JSON.
+            eprintln!("{} errors, {} warnings", errors, warnings);
+            if errors > 0 {
+                ::anyhow::bail!("check found {} errors", errors);
+            }
         }
-        // Note that this does not handle recompiling the Rust parts
-        // of the project. At least, not yet.
-        Target::Dev => {
-            server::start(opt, bins)?;
+        Target::Doc => {
+            let index = doc::doc(&opt.project_root, &config, &bins()?.elm)?;
+            println!("docs are at {}", index.display());
+        }
+        Target::Test {
+            ref junit,
+            no_e2e,
+            faults,
+        } => {
+            let mut report = testing::Report::default();
+            testing::cargo(&mut report, &opt.project_root);
+            testing::elm(&mut report, &config.elm.project_dir);
+            if !no_e2e {
+                let mut manifest = Manifest::load(&opt.project_root, false);
+                let built = bins()
+                    .and_then(|bins| build_site(&config, &rules, &mut manifest, &bins, false))
+                    .and_then(|_| build_app(&opt.project_root, false, false));
+                match built {
+                    Ok(server) => {
+                        e2e::run(&mut report, &opt.project_root, &server, &config, faults)
+                    }
+                    Err(e) => {
+                        report.run("e2e", "build", || testing::Outcome::Failed(e.to_string()))
+                    }
+                }
+            }
+            report.print_failures();
+            println!("{}", report.summary());
+            if let Some(junit) = junit {
+                report.write_junit(junit)?;
+            }
+            if report.failures() > 0 {
+                ::anyhow::bail!("{} tests failed", report.failures());
+            }
+        }
+        // The Elm side gets rebuilt here, and the supervisor
+        // rebuilds and restarts the app when the Rust side changes.
+        Target::Dev { .. } => {
+            let bins = bins()?;
+            let manifest = Manifest::load(&opt.project_root, false);
+            server::start(opt, config, bins, rules, manifest)?;
         }
         Target::Clean {
             html,
             elm,
             rust,
             doc,
+            fingerprints,
+            toolchain,
+            dry_run,
         } => {
-            let normal = NormalizedClean::new(html, elm, rust, doc);
-            if normal.html {
-                if normal.doc {
-                    // There are no docs for this target, yet.
-                } else {
-                    ::fsio::directory::delete(&opt.project_root.join("static"))
-                        .map_err(|e| ::anyhow::anyhow!("{}", e))?;
-                }
-            }
-            if normal.elm {
-                if normal.doc {
-                    // There are no docs for this target, yet.
-                } else {
-                    ::fsio::directory::delete(&opt.project_root.join("elm-stuff"))
-                        .map_err(|e| ::anyhow::anyhow!("{}", e))?;
-                }
-            }
-            if normal.rust {
-                if normal.doc {
-                    sp! { "cargo" ; "clean", "--doc",
-                    "--manifest-path", opt.project_root.join("Cargo.toml") };
-                } else {
-                    sp! { "cargo" ; "clean", "--manifest-path", opt.project_root.join("Cargo.toml") };
-                }
-            }
+            let normal = NormalizedClean::new(html, elm, rust, fingerprints, toolchain, doc);
+            clean(&opt.project_root, &config, &normal, dry_run)?;
         }
     }
     Ok(())
@@ -0,0 +1,258 @@
+//! Remembering what we built last time, so we don't build it again.
+//! Each step records a hash of everything that went into it,
+//! and hashes of what it wrote.
+//! A step is only skipped if both still match,
+//! so deleting the output, or the manifest, just means a full rebuild.
+//! Paths in it are relative to the project root, so it still works
+//! from a checkout somewhere else, like when CI restores it from a cache.
+use ::serde::{Deserialize, Serialize};
+use ::sha2::{Digest, Sha256};
+use ::std::collections::{BTreeMap, BTreeSet};
+use ::std::fs;
+use ::std::io;
+use ::std::path::{Path, PathBuf};
+use ::std::process::Command;
+
+/// Where the manifest lives, relative to the project root.
+/// It's under `target/` so that caching that for CI caches this too.
+pub(crate) const MANIFEST_PATH: &str = "target/fileshare-build/manifest.json";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+struct StepRecord {
+    inputs: String,
+    outputs: BTreeMap<PathBuf, String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub(crate) struct Manifest {
+    /// The version of every tool the last build used.
+    /// Just for the record; steps that care hash their tools' versions themselves.
+    #[serde(default)]
+    tools: BTreeMap<String, String>,
+    steps: BTreeMap<String, StepRecord>,
+    #[serde(skip)]
+    path: PathBuf,
+    #[serde(skip)]
+    root: PathBuf,
+    /// Pretend nothing is fresh.
+    #[serde(skip)]
+    force: bool,
+    /// The steps this build has been through, fresh or not.
+    #[serde(skip)]
+    current: BTreeSet<String>,
+}
+impl Manifest {
+    /// Load the manifest for a project.
+    /// If it's missing or we can't make sense of it,
+    /// we start from nothing, same as a clean build.
+    pub(crate) fn load(project_root: &Path, force: bool) -> Self {
+        let path = project_root.join(MANIFEST_PATH);
+        let mut manifest = fs::read(&path)
+            .ok()
+            .and_then(|x| ::serde_json::from_slice::<Self>(&x).ok())
+            .unwrap_or_default();
+        manifest.path = path;
+        manifest.root = project_root.to_path_buf();
+        manifest.force = force;
+        manifest
+    }
+
+    pub(crate) fn save(&self) -> ::anyhow::Result<()> {
+        ::fsio::file::ensure_exists(&self.path).map_err(|e| ::anyhow::anyhow!(e))?;
+        fs::write(&self.path, ::serde_json::to_vec_pretty(self)?)?;
+        Ok(())
+    }
+
+    /// Whether the step `key` can be skipped.
+    /// `inputs` should come from a [`Hasher`] fed everything the step depends on.
+    pub(crate) fn is_fresh(&mut self, key: &str, inputs: &str) -> bool {
+        self.current.insert(key.into());
+        if self.force {
+            return false;
+        }
+        match self.steps.get(key) {
+            Some(record) if record.inputs == inputs => record
+                .outputs
+                .iter()
+                .all(|(path, hash)| hash_file(&self.root.join(path)).ok().as_ref() == Some(hash)),
+            _ => false,
+        }
+    }
+
+    /// Note that the step `key` just ran, successfully, and wrote `outputs`.
+    pub(crate) fn record(
+        &mut self,
+        key: &str,
+        inputs: String,
+        outputs: &[&Path],
+    ) -> io::Result<()> {
+        let outputs = outputs
+            .iter()
+            .map(|&path| Ok((relative(&self.root, path).into(), hash_file(path)?)))
+            .collect::<io::Result<_>>()?;
+        self.steps
+            .insert(key.into(), StepRecord { inputs, outputs });
+        self.current.insert(key.into());
+        Ok(())
+    }
+
+    /// Everything the steps this build went through wrote,
+    /// as opposed to ones from earlier builds that didn't come up this time.
+    pub(crate) fn current_outputs(&self) -> impl Iterator<Item = PathBuf> + '_ {
+        self.current
+            .iter()
+            .filter_map(move |x| self.steps.get(x))
+            .flat_map(move |x| x.outputs.keys().map(move |path| self.root.join(path)))
+    }
+
+    /// The project root, which everything in here is relative to.
+    pub(crate) fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub(crate) fn set_tools(&mut self, tools: BTreeMap<String, String>) {
+        self.tools = tools;
+    }
+
+    /// Forget about a step, so it runs next time no matter what.
+    pub(crate) fn invalidate(&mut self, key: &str) {
+        self.steps.remove(key);
+    }
+
+    /// Forget every step whose key starts with `prefix`,
+    /// and say what they wrote, so it can be cleaned up.
+    pub(crate) fn forget_steps(&mut self, prefix: &str) -> Vec<PathBuf> {
+        self.forget_where(|key| key.starts_with(prefix))
+    }
+
+    /// Like [`Manifest::forget_steps`], but only the ones this build
+    /// hasn't been through, like steps for files that are gone.
+    pub(crate) fn forget_stale_steps(&mut self, prefix: &str) -> Vec<PathBuf> {
+        let current = ::std::mem::take(&mut self.current);
+        let outputs = self.forget_where(|key| key.starts_with(prefix) && !current.contains(key));
+        self.current = current;
+        outputs
+    }
+
+    fn forget_where(&mut self, forget: impl Fn(&str) -> bool) -> Vec<PathBuf> {
+        let keys: Vec<String> = self.steps.keys().filter(|x| forget(x)).cloned().collect();
+        let mut outputs = Vec::new();
+        for key in keys {
+            if let Some(record) = self.steps.remove(&key) {
+                outputs.extend(record.outputs.into_keys().map(|x| self.root.join(x)));
+            }
+        }
+        outputs
+    }
+}
+
+/// `path` relative to `root`, with forward slashes,
+/// for keys and hashes that don't care where the project is.
+pub(crate) fn relative(root: &Path, path: &Path) -> String {
+    path.strip_prefix(root)
+        .unwrap_or(path)
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Builds up the input hash for a step.
+#[derive(Clone)]
+pub(crate) struct Hasher {
+    sha: Sha256,
+    root: PathBuf,
+}
+impl Hasher {
+    /// File paths get hashed relative to `project_root`.
+    pub(crate) fn new(project_root: &Path) -> Self {
+        Self {
+            sha: Sha256::new(),
+            root: project_root.to_path_buf(),
+        }
+    }
+    /// Each piece is length prefixed, so `"ab", "c"` and `"a", "bc"` differ.
+    pub(crate) fn bytes(&mut self, bytes: &[u8]) -> &mut Self {
+        self.sha.update((bytes.len() as u64).to_le_bytes());
+        self.sha.update(bytes);
+        self
+    }
+    pub(crate) fn str(&mut self, s: &str) -> &mut Self {
+        self.bytes(s.as_bytes())
+    }
+    /// Hash a file's path and contents.
+    pub(crate) fn file(&mut self, path: &Path) -> io::Result<&mut Self> {
+        self.str(&relative(&self.root, path));
+        self.bytes(&fs::read(path)?);
+        Ok(self)
+    }
+    /// Hash a path, without reading it.
+    pub(crate) fn path(&mut self, path: &Path) -> &mut Self {
+        self.str(&relative(&self.root, path))
+    }
+    pub(crate) fn finish(&mut self) -> String {
+        ::hex::encode(self.sha.finalize_reset())
+    }
+}
+
+pub(crate) fn hash_file(path: &Path) -> io::Result<String> {
+    Ok(::hex::encode(Sha256::digest(&fs::read(path)?)))
+}
+
+/// Whatever a tool says when asked for `--version`.
+/// Upgrading a tool should rebuild everything it touched.
+pub(crate) fn tool_version(program: &Path) -> String {
+    match Command::new(program).arg("--version").output() {
+        Ok(x) if x.status.success() => String::from_utf8_lossy(&x.stdout).trim().into(),
+        _ => "unknown".into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The key and inputs `copy` would have for `src/a.txt`.
+    fn copy_step(root: &Path) -> (String, String) {
+        let key = format!("copy:{}", relative(root, &root.join("static/a.txt")));
+        let inputs = Hasher::new(root)
+            .file(&root.join("src/a.txt"))
+            .unwrap()
+            .finish();
+        (key, inputs)
+    }
+
+    #[test]
+    fn manifests_still_work_after_the_project_moves() {
+        let here = ::tempfile::tempdir().unwrap();
+        let there = ::tempfile::tempdir().unwrap();
+        for root in &[here.path(), there.path()] {
+            fs::create_dir_all(root.join("src")).unwrap();
+            fs::create_dir_all(root.join("static")).unwrap();
+            fs::write(root.join("src/a.txt"), "a").unwrap();
+            fs::write(root.join("static/a.txt"), "a").unwrap();
+        }
+
+        let mut manifest = Manifest::load(here.path(), false);
+        let (key, inputs) = copy_step(here.path());
+        assert!(!manifest.is_fresh(&key, &inputs));
+        manifest
+            .record(&key, inputs, &[&here.path().join("static/a.txt")])
+            .unwrap();
+        manifest.save().unwrap();
+        fs::create_dir_all(there.path().join("target/fileshare-build")).unwrap();
+        fs::copy(
+            here.path().join(MANIFEST_PATH),
+            there.path().join(MANIFEST_PATH),
+        )
+        .unwrap();
+
+        let mut manifest = Manifest::load(there.path(), false);
+        let (key, inputs) = copy_step(there.path());
+        assert!(manifest.is_fresh(&key, &inputs));
+        assert_eq!(
+            manifest.current_outputs().collect::<Vec<_>>(),
+            [there.path().join("static/a.txt")]
+        );
+    }
+}
@@ -0,0 +1,301 @@
+//! Shrinking the Elm output.
+//! Terser is the usual way, but it needs Node,
+//! so there's an in-process fallback built on swc's minifier,
+//! behind the `rust-minifier` feature.
+//! Both get the same Elm-safe compress options from the config.
+//! Either one can write a source map next to the bundle, as `main.js.map`.
+//! Elm doesn't make source maps itself, so these only get you
+//! from the minified bundle back to the JavaScript Elm wrote,
+//! but that's still a lot easier to read a stack trace against.
+use crate::config::{MinifyBackend, MinifyConfig};
+use crate::log;
+use crate::runner;
+use ::std::fs;
+use ::std::path::{Path, PathBuf};
+use ::std::process::Command;
+
+/// A minifier we can actually use, as opposed to one the config asked for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Minifier<'a> {
+    Terser(&'a Path),
+    Rust,
+}
+impl Minifier<'_> {
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            Minifier::Terser(_) => "terser",
+            Minifier::Rust => "swc",
+        }
+    }
+    /// What to hash into the build manifest,
+    /// so changing minifiers, or upgrading one, means rebuilding.
+    pub(crate) fn version(&self) -> String {
+        match self {
+            Minifier::Terser(terser) => crate::manifest::tool_version(terser),
+            Minifier::Rust => format!("swc in fileshare-build {}", env!("CARGO_PKG_VERSION")),
+        }
+    }
+}
+
+/// Pick a minifier, or none, going by the config and what's installed.
+pub(crate) fn choose<'a>(
+    config: &MinifyConfig,
+    terser: Option<&'a Path>,
+) -> ::anyhow::Result<Option<Minifier<'a>>> {
+    if !config.enabled {
+        return Ok(None);
+    }
+    Ok(Some(match (config.backend, terser) {
+        (MinifyBackend::Terser, Some(terser)) | (MinifyBackend::Auto, Some(terser)) => {
+            Minifier::Terser(terser)
+        }
+        (MinifyBackend::Terser, None) => {
+            ::anyhow::bail!("`minify.backend` is \"terser\", but terser isn't installed")
+        }
+        (MinifyBackend::Rust, _) | (MinifyBackend::Auto, None) => {
+            if cfg!(feature = "rust-minifier") {
+                Minifier::Rust
+            } else if config.backend == MinifyBackend::Rust {
+                ::anyhow::bail!(
+                    "`minify.backend` is \"rust\", but fileshare-build was built without the `rust-minifier` feature"
+                )
+            } else {
+                log::warning!(
+                    "minify",
+                    "no minifier available; install terser, or build fileshare-build with `rust-minifier`"
+                );
+                return Ok(None);
+            }
+        }
+    }))
+}
+
+/// Where the source map for a bundle goes.
+pub(crate) fn source_map_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".map");
+    PathBuf::from(name)
+}
+
+/// How the bundle refers to its source map.
+/// They're always next to each other, so the bare file name does it.
+fn source_map_url(path: &Path) -> String {
+    file_name(&source_map_path(path))
+}
+
+/// What the source map calls the unminified bundle.
+/// The text itself goes in the map, since the file gets minified over,
+/// so this only has to be relative, not to point anywhere in particular.
+fn source_name(path: &Path) -> String {
+    file_name(path)
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .map(|x| x.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+/// Minify a bundle in place, and say how much it helped.
+pub(crate) fn minify(
+    config: &MinifyConfig,
+    minifier: Minifier,
+    path: &Path,
+) -> ::anyhow::Result<()> {
+    let before = fs::metadata(path)?.len();
+    match minifier {
+        Minifier::Terser(terser) => with_terser(config, terser, path)?,
+        Minifier::Rust => with_rust(config, path)?,
+    }
+    let after = fs::metadata(path)?.len();
+    log::info!(
+        "minify",
+        "{}: {} -> {} bytes with {} ({:.1}%)",
+        path.display(),
+        before,
+        after,
+        minifier.name(),
+        if before == 0 {
+            100.0
+        } else {
+            after as f64 * 100.0 / before as f64
+        }
+    );
+    Ok(())
+}
+
+fn with_terser(config: &MinifyConfig, terser: &Path, path: &Path) -> ::anyhow::Result<()> {
+    let mut compress = Command::new(terser);
+    if config.source_maps {
+        // Terser names the source the way it was given on the command line,
+        // so give it just the file name.
+        if let Some(dir) = path.parent() {
+            compress.current_dir(dir);
+        }
+        compress.arg(source_name(path));
+    } else {
+        compress.arg(path);
+    }
+    compress.arg("--compress").arg(config.terser_compress());
+    let step = format!("terser {}", path.display());
+    if config.source_maps {
+        // A map has to survive both passes, so this is one pass instead.
+        // Terser writes it to the output path plus `.map`.
+        if config.mangle {
+            compress.arg("--mangle");
+        }
+        compress
+            .arg("--source-map")
+            .arg(format!("includeSources,url='{}'", source_map_url(path)))
+            .arg("--output")
+            .arg(path);
+        runner::Pipeline::new(step).pipe(compress).run()?;
+    } else if config.mangle {
+        let mut mangle = Command::new(terser);
+        mangle.arg("--mangle").arg("--output").arg(path);
+        runner::Pipeline::new(step)
+            .pipe(compress)
+            .pipe(mangle)
+            .run()?;
+    } else {
+        compress.arg("--output").arg(path);
+        runner::Pipeline::new(step).pipe(compress).run()?;
+    }
+    Ok(())
+}
+
+/// Our compress options, as the JSON terser would take them.
+/// swc's minifier understands terser's option format,
+/// which saves us translating them ourselves.
+#[cfg_attr(not(feature = "rust-minifier"), allow(dead_code))]
+fn terser_options_json(config: &MinifyConfig) -> ::serde_json::Value {
+    let mut options = ::serde_json::Map::new();
+    options.insert("pure_funcs".into(), config.pure_funcs.clone().into());
+    for option in &config.compress {
+        let (key, value) = match option.find('=') {
+            Some(i) => {
+                let value = &option[i + 1..];
+                (
+                    &option[..i],
+                    ::serde_json::from_str(value).unwrap_or_else(|_| value.into()),
+                )
+            }
+            None => (option.as_str(), true.into()),
+        };
+        options.insert(key.into(), value);
+    }
+    options.into()
+}
+
+#[cfg(not(feature = "rust-minifier"))]
+fn with_rust(_config: &MinifyConfig, _path: &Path) -> ::anyhow::Result<()> {
+    unreachable!("`choose` never picks the Rust minifier without the feature")
+}
+
+#[cfg(feature = "rust-minifier")]
+fn with_rust(config: &MinifyConfig, path: &Path) -> ::anyhow::Result<()> {
+    use ::swc_core::common::source_map::SourceMapGenConfig;
+    use ::swc_core::common::sync::Lrc;
+    use ::swc_core::common::{FileName, Globals, Mark, SourceMap, GLOBALS};
+    use ::swc_core::ecma::ast::{EsVersion, Program};
+    use ::swc_core::ecma::codegen::text_writer::JsWriter;
+    use ::swc_core::ecma::codegen::{Config as CodegenConfig, Emitter};
+    use ::swc_core::ecma::minifier::optimize;
+    use ::swc_core::ecma::minifier::option::terser::TerserCompressorOptions;
+    use ::swc_core::ecma::minifier::option::{ExtraOptions, MangleOptions, MinifyOptions};
+    use ::swc_core::ecma::parser::{parse_file_as_script, Syntax};
+    use ::swc_core::ecma::transforms::base::fixer::fixer;
+    use ::swc_core::ecma::transforms::base::resolver;
+    use ::swc_core::ecma::visit::FoldWith;
+
+    /// There's only the one file, so it always gets the same name,
+    /// and its text goes in the map.
+    struct Sources(String);
+    impl SourceMapGenConfig for Sources {
+        fn file_name_to_source(&self, _: &FileName) -> String {
+            self.0.clone()
+        }
+        fn inline_sources_content(&self, _: &FileName) -> bool {
+            true
+        }
+    }
+
+    let source = fs::read_to_string(path)?;
+    let terser_options: TerserCompressorOptions =
+        ::serde_json::from_value(terser_options_json(config))?;
+
+    let minified = GLOBALS.set(&Globals::new(), || -> ::anyhow::Result<Vec<u8>> {
+        let cm: Lrc<SourceMap> = Default::default();
+        let file = cm.new_source_file(FileName::Custom(source_name(path)), source);
+        let script = parse_file_as_script(
+            &file,
+            Syntax::Es(Default::default()),
+            EsVersion::Es5,
+            None,
+            &mut Vec::new(),
+        )
+        .map_err(|e| ::anyhow::anyhow!("couldn't parse {}: {:?}", path.display(), e.kind()))?;
+
+        let unresolved_mark = Mark::new();
+        let top_level_mark = Mark::new();
+        let program = Program::Script(script).fold_with(&mut resolver(
+            unresolved_mark,
+            top_level_mark,
+            false,
+        ));
+        let options = MinifyOptions {
+            compress: Some(terser_options.into_config(cm.clone())),
+            mangle: if config.mangle {
+                Some(MangleOptions::default())
+            } else {
+                None
+            },
+            ..Default::default()
+        };
+        let program = optimize(
+            program,
+            cm.clone(),
+            None,
+            None,
+            &options,
+            &ExtraOptions {
+                unresolved_mark,
+                top_level_mark,
+            },
+        )
+        .fold_with(&mut fixer(None));
+
+        let mut out = Vec::new();
+        let mut mappings = Vec::new();
+        {
+            let mut emitter = Emitter {
+                cfg: CodegenConfig::default().with_minify(true),
+                cm: cm.clone(),
+                comments: None,
+                wr: JsWriter::new(
+                    cm.clone(),
+                    "\n",
+                    &mut out,
+                    if config.source_maps {
+                        Some(&mut mappings)
+                    } else {
+                        None
+                    },
+                ),
+            };
+            emitter.emit_program(&program)?;
+        }
+        if config.source_maps {
+            let mut map = Vec::new();
+            cm.build_source_map_with_config(&mut mappings, None, Sources(source_name(path)))
+                .to_writer(&mut map)?;
+            fs::write(source_map_path(path), map)?;
+            out.extend_from_slice(
+                format!("\n//# sourceMappingURL={}\n", source_map_url(path)).as_bytes(),
+            );
+        }
+        Ok(out)
+    })?;
+    fs::write(path, minified)?;
+    Ok(())
+}
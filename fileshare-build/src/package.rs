@@ -0,0 +1,252 @@
+//! Bundling a release build up for deploying.
+//! Everything the server needs goes in one `.tar.zst`:
+//! the Rocket binary, the built site, a production `Rocket.toml`,
+//! and a systemd unit, all under a `fileshare-<version>/` directory.
+//!
+//! There's a `SHA256SUMS` in the bundle, so after unpacking,
+//! `sha256sum -c SHA256SUMS` checks everything made it over.
+//! The bundle itself gets a `.sha256` next to it, in the same format.
+//!
+//! `static/` only gets what the build wrote, going by the build manifest,
+//! so source maps, which release builds don't serve,
+//! and whatever's left over in the output directory, stay out.
+//! With `--embed`, the site is already in the binary,
+//! so `static/` is left out altogether.
+//!
+//! Certs don't go in the bundle. The `Rocket.toml` points at
+//! where the config says they live on the server.
+use crate::compress;
+use crate::config::{Config, PackageConfig};
+use crate::fingerprint::{self, AssetMap};
+use crate::manifest::Manifest;
+use ::serde::Deserialize;
+use ::std::collections::BTreeSet;
+use ::std::fs::{self, File};
+use ::std::io::{self, Write};
+use ::std::path::{Path, PathBuf};
+
+/// The part of the app's `Cargo.toml` we care about.
+#[derive(Deserialize)]
+struct CargoToml {
+    package: CargoPackage,
+}
+#[derive(Deserialize)]
+pub(crate) struct CargoPackage {
+    pub(crate) name: String,
+    pub(crate) version: String,
+}
+
+/// The app's name and version, from its `Cargo.toml`.
+pub(crate) fn app_package(project_root: &Path) -> ::anyhow::Result<CargoPackage> {
+    let cargo_toml: CargoToml =
+        ::toml::from_str(&fs::read_to_string(project_root.join("Cargo.toml"))?)?;
+    Ok(cargo_toml.package)
+}
+
+/// Writes files into the tarball, remembering their hashes as it goes.
+struct Bundle<W: Write> {
+    tar: ::tar::Builder<W>,
+    prefix: PathBuf,
+    sums: Vec<(String, String)>,
+}
+impl<W: Write> Bundle<W> {
+    fn file(&mut self, name: &str, path: &Path) -> ::anyhow::Result<()> {
+        self.sums
+            .push((crate::manifest::hash_file(path)?, name.into()));
+        self.tar
+            .append_path_with_name(path, self.prefix.join(name))?;
+        Ok(())
+    }
+
+    fn generated(&mut self, name: &str, data: &[u8], mode: u32) -> io::Result<()> {
+        self.sums.push((
+            ::hex::encode(<::sha2::Sha256 as ::sha2::Digest>::digest(data)),
+            name.into(),
+        ));
+        let mut header = ::tar::Header::new_gnu();
+        header.set_size(data.len() as u64);
+        header.set_mode(mode);
+        header.set_mtime(
+            ::std::time::SystemTime::now()
+                .duration_since(::std::time::UNIX_EPOCH)
+                .map(|x| x.as_secs())
+                .unwrap_or(0),
+        );
+        header.set_cksum();
+        self.tar
+            .append_data(&mut header, self.prefix.join(name), data)
+    }
+
+    /// Add `SHA256SUMS`, covering everything else, and finish up.
+    fn finish(mut self) -> io::Result<W> {
+        let mut sums = String::new();
+        for (hash, name) in &self.sums {
+            sums.push_str(&format!("{}  {}\n", hash, name));
+        }
+        self.generated("SHA256SUMS", sums.as_bytes(), 0o644)?;
+        self.tar.into_inner()
+    }
+}
+
+/// What goes in `static/`, or into the binary with `--embed`:
+/// what this build wrote to the output directory, apart from source maps,
+/// and the fingerprinted and precompressed copies of that.
+/// Also the asset map, cut down to match.
+pub(crate) fn site_files(output_dir: &Path, manifest: &Manifest) -> (BTreeSet<PathBuf>, AssetMap) {
+    let mut plain: BTreeSet<PathBuf> = manifest
+        .current_outputs()
+        .filter(|x| x.starts_with(output_dir))
+        .filter(|x| x.extension() != Some(::std::ffi::OsStr::new("map")))
+        // Precompressed copies are picked up below, along with the ones
+        // for the fingerprinted names, but a `.tar.gz` that was just copied
+        // over is an asset like any other.
+        .filter(|x| !is_precompressed(x))
+        .collect();
+    // Written every build, rather than being a step.
+    plain.insert(output_dir.join(crate::protocol::RELOAD_CONFIG));
+    let assets: AssetMap = fingerprint::read_asset_map(output_dir)
+        .into_iter()
+        .filter(|(name, _)| plain.contains(&output_dir.join(name)))
+        .collect();
+    let mut files = plain;
+    files.extend(assets.values().map(|x| output_dir.join(x)));
+    for file in files.clone() {
+        for encoding in compress::ENCODINGS {
+            files.insert(compress::sibling(&file, encoding));
+        }
+    }
+    files.retain(|x| x.is_file());
+    (files, assets)
+}
+
+/// Whether `path` is a `.gz` or `.br` copy of a file next to it.
+pub(crate) fn is_precompressed(path: &Path) -> bool {
+    let encoded = path
+        .extension()
+        .and_then(|x| x.to_str())
+        .is_some_and(|x| compress::ENCODINGS.contains(&x));
+    encoded && path.with_extension("").is_file()
+}
+
+/// Bundle up a release build that's already been done,
+/// with the app's executable at `binary`.
+/// Returns the path to the bundle.
+pub(crate) fn package(
+    project_root: &Path,
+    config: &Config,
+    manifest: &Manifest,
+    binary: &Path,
+    embedded: bool,
+) -> ::anyhow::Result<PathBuf> {
+    let CargoPackage { name, version } = app_package(project_root)?;
+
+    let prefix = format!("{}-{}", name, version);
+    let bundle_name = format!("{}-{}.tar.zst", prefix, crate::toolchain::platform());
+    let out_dir = &config.package.output_dir;
+    fs::create_dir_all(out_dir)?;
+    let bundle_path = out_dir.join(&bundle_name);
+
+    let encoder = ::zstd::Encoder::new(File::create(&bundle_path)?, 19)?;
+    let mut bundle = Bundle {
+        tar: ::tar::Builder::new(encoder),
+        prefix: prefix.into(),
+        sums: Vec::new(),
+    };
+    bundle.file(&name, binary)?;
+    if !embedded {
+        let site = &config.build.output_dir;
+        let (files, assets) = site_files(site, manifest);
+        for file in files {
+            let rel = file
+                .strip_prefix(site)?
+                .components()
+                .map(|c| c.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/");
+            bundle.file(&format!("static/{}", rel), &file)?;
+        }
+        if site.join(fingerprint::ASSET_MANIFEST).is_file() {
+            bundle.generated(
+                &format!("static/{}", fingerprint::ASSET_MANIFEST),
+                &::serde_json::to_vec_pretty(&assets)?,
+                0o644,
+            )?;
+        }
+    }
+    bundle.generated(
+        "Rocket.toml",
+        rocket_toml(&config.package, &name, &version).as_bytes(),
+        0o644,
+    )?;
+    bundle.generated(
+        &format!("{}.service", name),
+        systemd_unit(&config.package, &name).as_bytes(),
+        0o644,
+    )?;
+    bundle.finish()?.finish()?;
+
+    let mut sum = File::create(out_dir.join(format!("{}.sha256", bundle_name)))?;
+    writeln!(
+        sum,
+        "{}  {}",
+        crate::manifest::hash_file(&bundle_path)?,
+        bundle_name
+    )?;
+    Ok(bundle_path)
+}
+
+fn rocket_toml(config: &PackageConfig, name: &str, version: &str) -> String {
+    format!(
+        r#"# Generated by fileshare-build for {name} {version}.
+# The systemd unit sets ROCKET_ENV=production, so that's the section that counts.
+[production]
+address = "{address}"
+port = {port}
+
+[production.tls]
+certs = "{certs}"
+key = "{key}"
+"#,
+        name = name,
+        version = version,
+        address = config.address,
+        port = config.port,
+        certs = config.tls_certs.display(),
+        key = config.tls_key.display(),
+    )
+}
+
+fn systemd_unit(config: &PackageConfig, name: &str) -> String {
+    let dir = config.install_dir.display();
+    format!(
+        r#"# Generated by fileshare-build.
+# Unpack the bundle with `tar --zstd -xf <bundle> --strip-components=1 -C {dir}`,
+# then link or copy this into /etc/systemd/system.
+[Unit]
+Description={name}
+After=network-online.target
+Wants=network-online.target
+
+[Service]
+Type=simple
+User={user}
+WorkingDirectory={dir}
+ExecStart={dir}/{name}
+Environment=ROCKET_ENV=production
+Environment=FILESHARE_STATIC={dir}/static
+Restart=on-failure
+# Binding to 443 without being root.
+AmbientCapabilities=CAP_NET_BIND_SERVICE
+NoNewPrivileges=true
+ProtectSystem=full
+ProtectHome=true
+PrivateTmp=true
+
+[Install]
+WantedBy=multi-user.target
+"#,
+        name = name,
+        user = config.user,
+        dir = dir,
+    )
+}
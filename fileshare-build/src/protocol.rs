@@ -0,0 +1,147 @@
+//! What the dev server and `reload.js` say to each other over the WebSocket.
+//! Every message is JSON, like `{"type": "refresh_page", "data": 1234}`.
+//!
+//! Both sides start with a `hello`, saying which [`PROTOCOL`] they speak,
+//! and what they can do. If the versions don't match, the page gets told to
+//! reload itself, since `reload.js` is probably stale, and the server hangs up.
+//! After that, the server sends a `ping` every [`PING_INTERVAL`],
+//! and the client answers with a `pong`. Either side that hears nothing
+//! for [`IDLE_TIMEOUT`] gives up on the connection.
+//! The client reconnects after that, backing off.
+use ::serde::{Deserialize, Serialize};
+use ::std::path::Path;
+use ::std::time::Duration;
+
+/// Bump this whenever a message changes shape, and in `reload.js` too.
+pub(crate) const PROTOCOL: u32 = 1;
+
+/// What the server can do, so clients can tell.
+pub(crate) const CAPABILITIES: &[&str] =
+    &["refresh_page", "display_error", "backend_status", "reports"];
+
+/// Written next to the assets, and loaded before `reload.js`,
+/// so it knows which port the dev server's on.
+pub(crate) const RELOAD_CONFIG: &str = "reload-config.js";
+
+pub(crate) const PING_INTERVAL: Duration = Duration::from_secs(15);
+/// A few pings' worth, so one slow pong doesn't count.
+pub(crate) const IDLE_TIMEOUT: Duration = Duration::from_secs(45);
+
+/// Tell `reload.js` where the dev server is, or that there isn't one.
+/// It uses the page's own host name, since that's what the browser
+/// already knows reaches us.
+pub(crate) fn write_reload_config(output_dir: &Path, port: Option<u16>) -> ::std::io::Result<()> {
+    let port = port.map_or_else(|| "null".to_owned(), |x| x.to_string());
+    ::std::fs::create_dir_all(output_dir)?;
+    ::std::fs::write(
+        output_dir.join(RELOAD_CONFIG),
+        format!(
+            "// Written by fileshare-build, for reload.js.\nwindow.reload_config = {{ port: {} }};\n",
+            port
+        ),
+    )
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub(crate) struct RefreshToken(u64);
+impl RefreshToken {
+    pub(crate) fn new() -> Self {
+        use ::rand::Rng;
+        // Small enough to survive being a JavaScript number.
+        Self(::rand::thread_rng().gen_range(0, 1 << 53))
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", content = "data", rename_all = "snake_case")]
+pub(crate) enum BrowserAction {
+    // To prevent infinite reloading,
+    // we generate a token to be associated with the
+    // latest page refresh.
+    // The client will use localStorage to keep
+    // the last page refresh token they received,
+    // and if they receive it again, ignore the refresh.
+    // The only operation we need the RefreshToken to support
+    // is equality comparison.
+    RefreshPage(RefreshToken),
+    DisplayError(String),
+    // The Rocket server stopped, and why.
+    BackendDown(String),
+    // The Rocket server's listening again.
+    // It might be running different code now,
+    // so this is a page refresh too.
+    BackendUp(RefreshToken),
+}
+
+impl BrowserAction {
+    /// What it's called on the wire.
+    pub(crate) fn kind(&self) -> &'static str {
+        match self {
+            Self::RefreshPage(_) => "refresh_page",
+            Self::DisplayError(_) => "display_error",
+            Self::BackendDown(_) => "backend_down",
+            Self::BackendUp(_) => "backend_up",
+        }
+    }
+
+    pub(crate) fn token(&self) -> Option<u64> {
+        match self {
+            Self::RefreshPage(x) | Self::BackendUp(x) => Some(x.0),
+            Self::DisplayError(_) | Self::BackendDown(_) => None,
+        }
+    }
+}
+
+/// Everything else the server says, which isn't about the site.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", content = "data", rename_all = "snake_case")]
+pub(crate) enum Control {
+    Hello {
+        protocol: u32,
+        capabilities: &'static [&'static str],
+    },
+    Ping(u64),
+    // The client speaks some other version.
+    // The page should reload, and pick up a `reload.js` that matches.
+    Outdated {
+        protocol: u32,
+    },
+}
+
+/// Anything the server sends.
+/// Both kinds are tagged the same way, so this adds nothing on the wire.
+#[derive(Debug, Clone, Serialize)]
+#[serde(untagged)]
+pub(crate) enum ServerMessage {
+    Control(Control),
+    Action(BrowserAction),
+}
+
+/// What the reload client tells us.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", content = "data", rename_all = "snake_case")]
+pub(crate) enum ClientMessage {
+    // Sent first thing on every connection.
+    Hello {
+        protocol: u32,
+        // Made up by the client, once per tab.
+        session: String,
+        user_agent: String,
+        page: String,
+        #[serde(default)]
+        capabilities: Vec<String>,
+    },
+    Pong(u64),
+    Report(BrowserReport),
+}
+
+/// Something that went wrong in a page.
+#[derive(Debug, Deserialize)]
+pub(crate) struct BrowserReport {
+    // `console.error`, `exception`, `rejection`, or `elm-port`.
+    pub(crate) kind: String,
+    pub(crate) message: String,
+    // Like `https://localhost:8000/main.js:12:5`, when the browser says.
+    pub(crate) source: Option<String>,
+    pub(crate) stack: Option<String>,
+}
@@ -0,0 +1,183 @@
+//! The one place that decides which source files matter.
+//! Both `copy()` and the dev server's watcher go through this,
+//! so they can't disagree about what counts as a change.
+use crate::config::AssetsConfig;
+use ::globset::{Glob, GlobSet, GlobSetBuilder};
+use ::ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ::ignore::Match;
+use ::std::path::{Path, PathBuf};
+use ::walkdir::WalkDir;
+
+/// Editor temp files, lock files, and OS junk.
+/// Written in `.gitignore` syntax.
+pub(crate) const DEFAULT_IGNORES: &[&str] = &[
+    ".git/",
+    // Emacs
+    "*~",
+    "#*#",
+    ".#*",
+    // Vim
+    "*.swp",
+    "*.swo",
+    "*.swx",
+    ".*.sw?",
+    "4913",
+    // JetBrains safe writes
+    "*___jb_tmp___",
+    "*___jb_old___",
+    // Gedit and friends
+    ".goutputstream-*",
+    // LibreOffice
+    ".~lock.*#",
+    // macOS and Windows
+    ".DS_Store",
+    "._*",
+    "Thumbs.db",
+    "desktop.ini",
+    // Everybody else
+    "*.tmp",
+    "*.bak",
+];
+
+/// What a source file is for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Kind {
+    /// Gets copied into the output as is.
+    Copy,
+    Elm,
+    Rust,
+}
+
+pub(crate) struct Rules {
+    root: PathBuf,
+    copy: GlobSet,
+    elm: GlobSet,
+    rust: GlobSet,
+    /// Every `.gitignore` and `.ignore` in the project,
+    /// deepest first, so that the first one with an opinion wins.
+    ignore_files: Vec<Gitignore>,
+    /// The defaults plus whatever the config adds.
+    /// This one gets the final say.
+    overrides: Gitignore,
+}
+impl Rules {
+    /// Build the rule set for a project.
+    /// `project_root` should be canonical, since the watcher hands us
+    /// absolute paths.
+    pub(crate) fn new(project_root: &Path, config: &AssetsConfig) -> ::anyhow::Result<Self> {
+        let mut builder = GlobSetBuilder::new();
+        for glob in &config.include {
+            builder.add(Glob::new(glob)?);
+        }
+        let copy = builder.build()?;
+        let elm = GlobSetBuilder::new().add(Glob::new("*.elm")?).build()?;
+        let rust = GlobSetBuilder::new().add(Glob::new("*.rs")?).build()?;
+
+        let mut overrides = GitignoreBuilder::new(project_root);
+        if config.default_ignores {
+            for line in DEFAULT_IGNORES {
+                overrides.add_line(None, line)?;
+            }
+        }
+        for line in &config.ignore {
+            overrides.add_line(None, line)?;
+        }
+        let overrides = overrides.build()?;
+
+        let mut rules = Self {
+            root: project_root.to_path_buf(),
+            copy,
+            elm,
+            rust,
+            ignore_files: Vec::new(),
+            overrides,
+        };
+        if config.gitignore {
+            rules.ignore_files = rules.find_ignore_files()?;
+        }
+        Ok(rules)
+    }
+
+    /// Collect ignore files from the whole project.
+    /// We skip anything the defaults already rule out,
+    /// along with build output, which is huge and never has
+    /// anything we care about.
+    fn find_ignore_files(&self) -> ::anyhow::Result<Vec<Gitignore>> {
+        let skip = ["target", "elm-stuff", "node_modules"];
+        let mut found = Vec::new();
+        let walker = WalkDir::new(&self.root).into_iter().filter_entry(|e| {
+            let name = e.file_name().to_string_lossy();
+            let is_dir = e.file_type().is_dir();
+            let is_output = is_dir && e.depth() == 1 && skip.contains(&name.as_ref());
+            !is_output && !self.overrides.matched(e.path(), is_dir).is_ignore()
+        });
+        for entry in walker {
+            let entry = entry?;
+            let name = entry.file_name();
+            // `.ignore` beats `.gitignore` in the same directory,
+            // same as ripgrep. We push it later, and reverse below.
+            if name == ".gitignore" || name == ".ignore" {
+                let (gi, err) = Gitignore::new(entry.path());
+                if let Some(err) = err {
+                    eprintln!("warning: {}: {}", entry.path().display(), err);
+                }
+                found.push((entry.depth(), name == ".ignore", gi));
+            }
+        }
+        found.sort_by_key(|(depth, dot_ignore, _)| (*depth, *dot_ignore));
+        Ok(found.into_iter().rev().map(|(_, _, gi)| gi).collect())
+    }
+
+    /// Whether we should act like this path doesn't exist.
+    pub(crate) fn is_ignored(&self, path: &Path, is_dir: bool) -> bool {
+        if !path.starts_with(&self.root) {
+            return true;
+        }
+        match self.overrides.matched_path_or_any_parents(path, is_dir) {
+            Match::Ignore(_) => return true,
+            Match::Whitelist(_) => return false,
+            Match::None => (),
+        }
+        for gi in &self.ignore_files {
+            if !path.starts_with(gi.path()) {
+                continue;
+            }
+            match gi.matched_path_or_any_parents(path, is_dir) {
+                Match::Ignore(_) => return true,
+                Match::Whitelist(_) => return false,
+                Match::None => (),
+            }
+        }
+        false
+    }
+
+    /// Figure out what a file is for, if anything.
+    /// Ignored files are for nothing.
+    pub(crate) fn classify(&self, path: &Path) -> Option<Kind> {
+        if self.is_ignored(path, path.is_dir()) {
+            None
+        } else if self.copy.is_match(path) {
+            Some(Kind::Copy)
+        } else if self.elm.is_match(path) {
+            Some(Kind::Elm)
+        } else if self.rust.is_match(path) {
+            Some(Kind::Rust)
+        } else {
+            None
+        }
+    }
+
+    /// Walk a directory, skipping anything ignored,
+    /// and yield the files we care about.
+    pub(crate) fn walk<'a>(&'a self, dir: &Path) -> impl Iterator<Item = (PathBuf, Kind)> + 'a {
+        WalkDir::new(dir)
+            .into_iter()
+            .filter_entry(move |e| !self.is_ignored(e.path(), e.file_type().is_dir()))
+            .filter_map(Result::ok)
+            .filter(|e| e.file_type().is_file())
+            .filter_map(move |e| {
+                let kind = self.classify(e.path())?;
+                Some((e.into_path(), kind))
+            })
+    }
+}
@@ -0,0 +1,241 @@
+//! Running other programs, and actually noticing when they fail.
+//! Every step gets a name, so when something goes wrong
+//! we can say which step it was, and what it printed to stderr,
+//! unless that's already been passed through.
+//! Whatever gets passed through to our output is labelled
+//! with the program it came from, stdout and stderr alike.
+use crate::log;
+use crate::OutputMethod;
+use ::std::io::{self, BufRead, BufReader, Read};
+use ::std::process::{Child, Command, Output, Stdio};
+use ::std::thread::{self, JoinHandle};
+
+#[derive(Debug, ::thiserror::Error)]
+pub(crate) enum StepError {
+    #[error("{step}: couldn't run `{program}`: {source}")]
+    Spawn {
+        step: String,
+        program: String,
+        #[source]
+        source: io::Error,
+    },
+    #[error("{step}: couldn't read what `{program}` printed: {source}")]
+    Read {
+        step: String,
+        program: String,
+        #[source]
+        source: io::Error,
+    },
+    #[error("{step}: `{program}` failed ({}){}", .output.status, captured_stderr(.output, *.forwarded))]
+    Failed {
+        step: String,
+        program: String,
+        output: Output,
+        /// Whether stderr's been passed through already.
+        forwarded: bool,
+    },
+}
+/// Stderr for an error message, if nobody's seen it yet.
+fn captured_stderr(output: &Output, forwarded: bool) -> String {
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if forwarded || stderr.trim().is_empty() {
+        String::new()
+    } else {
+        format!("\n{}", stderr.trim_end())
+    }
+}
+fn program_name(cmd: &Command) -> String {
+    let program = cmd.get_program();
+    match ::std::path::Path::new(program).file_name() {
+        Some(x) => x.to_string_lossy().into_owned(),
+        None => program.to_string_lossy().into_owned(),
+    }
+}
+
+/// Pass a child's stdout or stderr through to ours a line at a time,
+/// labelled with the program's name, keeping a copy.
+fn tee<R: Read + Send + 'static>(program: String, output: R, stderr: bool) -> JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut kept = Vec::new();
+        for line in BufReader::new(output).split(b'\n') {
+            let line = match line {
+                Ok(x) => x,
+                Err(_) => break,
+            };
+            log::child_line(&program, stderr, &String::from_utf8_lossy(&line));
+            kept.extend_from_slice(&line);
+            kept.push(b'\n');
+        }
+        kept
+    })
+}
+
+fn check(
+    step: &str,
+    program: String,
+    output: Output,
+    forwarded: bool,
+) -> Result<Output, StepError> {
+    if output.status.success() {
+        Ok(output)
+    } else {
+        Err(StepError::Failed {
+            step: step.into(),
+            program,
+            output,
+            forwarded,
+        })
+    }
+}
+
+/// Run a single command to completion.
+/// With [`OutputMethod::Forward`], what's returned is a copy
+/// of what was passed through.
+/// With [`OutputMethod::ForwardStderr`], stdout isn't passed through,
+/// just returned, for when it's for us rather than people.
+pub(crate) fn run(step: &str, cmd: &mut Command, out: OutputMethod) -> Result<Output, StepError> {
+    let program = program_name(cmd);
+    let spawn_error = |source| StepError::Spawn {
+        step: step.into(),
+        program: program.clone(),
+        source,
+    };
+    let output = match out {
+        OutputMethod::Capture => cmd.output().map_err(spawn_error)?,
+        OutputMethod::Forward | OutputMethod::ForwardStderr => {
+            let mut child = cmd
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .spawn()
+                .map_err(spawn_error)?;
+            let stderr = tee(
+                program.clone(),
+                child.stderr.take().expect("stderr was piped"),
+                true,
+            );
+            let mut stdout = child.stdout.take().expect("stdout was piped");
+            let stdout = match out {
+                OutputMethod::ForwardStderr => {
+                    let mut kept = Vec::new();
+                    if let Err(source) = stdout.read_to_end(&mut kept) {
+                        let _ = child.kill();
+                        let _ = child.wait();
+                        return Err(StepError::Read {
+                            step: step.into(),
+                            program,
+                            source,
+                        });
+                    }
+                    kept
+                }
+                _ => tee(program.clone(), stdout, false)
+                    .join()
+                    .unwrap_or_default(),
+            };
+            let status = child.wait().map_err(spawn_error)?;
+            Output {
+                status,
+                stdout,
+                stderr: stderr.join().unwrap_or_default(),
+            }
+        }
+    };
+    let forwarded = !matches!(out, OutputMethod::Capture);
+    check(step, program, output, forwarded)
+}
+
+/// Commands whose stdout feeds the next one's stdin, like `a | b` in a shell.
+/// The last one's stdout goes to ours, labelled.
+/// Unlike a shell, any of them failing fails the whole thing.
+pub(crate) struct Pipeline {
+    step: String,
+    commands: Vec<Command>,
+}
+impl Pipeline {
+    pub(crate) fn new(step: impl Into<String>) -> Self {
+        Self {
+            step: step.into(),
+            commands: Vec::new(),
+        }
+    }
+    pub(crate) fn pipe(mut self, cmd: Command) -> Self {
+        self.commands.push(cmd);
+        self
+    }
+    pub(crate) fn run(self) -> Result<(), StepError> {
+        let step = self.step;
+        let count = self.commands.len();
+        let mut running: Vec<(String, Child, JoinHandle<Vec<u8>>)> = Vec::with_capacity(count);
+        let mut previous: Option<Stdio> = None;
+        let mut last_stdout = None;
+        for mut cmd in self.commands {
+            let program = program_name(&cmd);
+            if let Some(stdin) = previous.take() {
+                cmd.stdin(stdin);
+            }
+            let mut child = match cmd.stdout(Stdio::piped()).stderr(Stdio::piped()).spawn() {
+                Ok(x) => x,
+                Err(source) => {
+                    // Don't leave the earlier ones blocked on a pipe nobody reads.
+                    for (_, mut child, _) in running {
+                        let _ = child.kill();
+                        let _ = child.wait();
+                    }
+                    return Err(StepError::Spawn {
+                        step,
+                        program,
+                        source,
+                    });
+                }
+            };
+            let stdout = child.stdout.take().expect("stdout was piped");
+            if running.len() + 1 < count {
+                previous = Some(Stdio::from(stdout));
+            } else {
+                last_stdout = Some(tee(program.clone(), stdout, false));
+            }
+            let stderr = tee(
+                program.clone(),
+                child.stderr.take().expect("stderr was piped"),
+                true,
+            );
+            running.push((program, child, stderr));
+        }
+        // Nothing's kept, since it's all gone to ours already.
+        if let Some(x) = last_stdout {
+            let _ = x.join();
+        }
+        let mut failure = None;
+        for (program, mut child, stderr) in running {
+            let status = child.wait();
+            let stderr = stderr.join().unwrap_or_default();
+            let status = match status {
+                Ok(x) => x,
+                Err(source) => {
+                    failure.get_or_insert(StepError::Spawn {
+                        step: step.clone(),
+                        program,
+                        source,
+                    });
+                    continue;
+                }
+            };
+            if let Err(e) = check(
+                &step,
+                program,
+                Output {
+                    status,
+                    stdout: Vec::new(),
+                    stderr,
+                },
+                true,
+            ) {
+                failure.get_or_insert(e);
+            }
+        }
+        match failure {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+}
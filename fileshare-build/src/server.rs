@@ -1,30 +1,30 @@
-use super::OutputMethod;
+use crate::config::Config;
+use crate::faults;
+use crate::log;
+use crate::manifest::Manifest;
+use crate::protocol::{
+    self, BrowserAction, ClientMessage, Control, RefreshToken, ServerMessage, CAPABILITIES,
+    IDLE_TIMEOUT, PING_INTERVAL, PROTOCOL,
+};
+use crate::rules::Rules;
+use crate::status::Status;
+use crate::supervisor;
+use crate::watch::{self, Changes, Rebuilder};
 use crate::Binaries;
-use crate::Opt;
-use crate::{copy, elm};
-use ::futures::SinkExt;
-use ::globset::{Glob, GlobSetBuilder};
-use ::notify::{DebouncedEvent, RecommendedWatcher, RecursiveMode, Watcher};
+use crate::{Opt, Target};
+use ::futures::{SinkExt, StreamExt};
 use ::serde::Deserialize;
+use ::std::net::SocketAddr;
 use ::std::path::Path;
-use ::std::process;
+use ::std::pin::Pin;
+use ::std::sync::Arc;
+use ::std::task::{Context, Poll};
 use ::std::thread;
+use ::std::time::{Duration, Instant};
+use ::tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use ::tokio_rustls::rustls;
-use ::walkdir::WalkDir;
 use tungstenite::Message;
 
-fn event_path(event: &DebouncedEvent) -> Option<&Path> {
-    match event {
-        DebouncedEvent::NoticeRemove(x)
-        | DebouncedEvent::Create(x)
-        | DebouncedEvent::Write(x)
-        | DebouncedEvent::Chmod(x)
-        | DebouncedEvent::Remove(x)
-        | DebouncedEvent::Rename(x, _) => Some(x),
-        _ => None,
-    }
-}
-
 // This TLS stuff... Is probably going to be used in the other part, too.
 // When I'm setting up large uploads via WebSockets.
 // I'll probably throw it into another crate in the workspace.
@@ -80,7 +80,7 @@ fn make_server_tls(project_root: &Path) -> rustls::ServerConfig {
             .expect("couldn't open project Rocket.toml"),
     )
     .unwrap();
-    println!("{:?}", rocket_cfg);
+    log::debug!("dev", "{:?}", rocket_cfg);
     let mut tls_config = rustls::ServerConfig::new(rustls::NoClientAuth::new());
     let certs = load_certs(&rocket_cfg.global.tls.certs);
     let privkey = load_private_key(&rocket_cfg.global.tls.key);
@@ -91,240 +91,547 @@ fn make_server_tls(project_root: &Path) -> rustls::ServerConfig {
 
     tls_config
 }
-#[derive(Debug, Clone, PartialEq, Eq, ::serde::Serialize)]
-struct RefreshToken(u64);
-impl RefreshToken {
-    fn new() -> Self {
-        use ::rand::Rng;
-        Self(::rand::thread_rng().gen())
-    }
-}
-#[derive(Debug, Clone, ::serde::Serialize)]
-enum BrowserAction {
-    // To prevent infinite reloading,
-    // we generate a token to be associated with the
-    // latest page refresh.
-    // The client will use localStorage to keep
-    // the last page refresh token they received,
-    // and if they receive it again, ignore the refresh.
-    // The only operation we need the RefreshToken to support
-    // is equality comparison.
-    RefreshPage(RefreshToken),
-    DisplayError(String),
-}
 // If we start trying to do granular module reloading in the browser,
 // this will need to change to a broadcast channel of some sort.
 // For now, we assume that a page reload, which will bring the browser
 // to the correct state, is the only response other than showing the current error.
 #[derive(Debug, Clone, ::serde::Serialize)]
-enum ServerAction {
+pub(crate) enum ServerAction {
     Reload(RefreshToken),
 }
+/// Just the browser and its major version, out of a whole user agent.
+fn browser_name(user_agent: &str) -> String {
+    let version = |product: &str| {
+        user_agent
+            .split(' ')
+            .find_map(|x| x.strip_prefix(product)?.strip_prefix('/'))
+            .map(|x| x.split('.').next().unwrap_or(x).to_string())
+    };
+    // Order matters, since Edge and Opera claim to be Chrome,
+    // and everything claims to be Safari.
+    for (product, name) in &[
+        ("Firefox", "Firefox"),
+        ("Edg", "Edge"),
+        ("OPR", "Opera"),
+        ("Chrome", "Chrome"),
+    ] {
+        if let Some(v) = version(product) {
+            return format!("{} {}", name, v);
+        }
+    }
+    match (version("Safari"), version("Version")) {
+        (Some(_), Some(v)) => format!("Safari {}", v),
+        _ => user_agent.to_string(),
+    }
+}
+
+/// Who's on the other end of a connection.
+struct Client {
+    /// For the status page.
+    id: u64,
+    /// Until the client says hello, all we know is where it's from.
+    tag: String,
+    last_heard: Instant,
+}
+
+/// Deal with a message from a browser.
+/// Returns whether to keep talking.
+fn client_message(client: &mut Client, status: &Status, text: &str) -> bool {
+    client.last_heard = Instant::now();
+    match ::serde_json::from_str::<ClientMessage>(text) {
+        Ok(ClientMessage::Hello {
+            protocol,
+            session,
+            user_agent,
+            page,
+            capabilities,
+        }) => {
+            client.tag = format!("{} {}", session, browser_name(&user_agent));
+            status.hello(client.id, &client.tag, &user_agent, &page);
+            if protocol != PROTOCOL {
+                log::warning!(
+                    "ws",
+                    "[{}] speaks protocol {}, not {}; telling it to reload",
+                    client.tag,
+                    protocol,
+                    PROTOCOL
+                );
+                return false;
+            }
+            log::debug!(
+                "browser",
+                "[{}] connected on {} ({}), can do {}",
+                client.tag,
+                page,
+                user_agent,
+                capabilities.join(", ")
+            );
+        }
+        Ok(ClientMessage::Pong(id)) => log::debug!("ws", "[{}] pong {}", client.tag, id),
+        Ok(ClientMessage::Report(report)) => {
+            let mut text = format!("[{}] {}: {}", client.tag, report.kind, report.message);
+            if let Some(source) = report.source {
+                text.push_str(&format!("\n    at {}", source));
+            }
+            if let Some(stack) = report.stack {
+                for line in stack.lines() {
+                    text.push_str(&format!("\n    {}", line.trim()));
+                }
+            }
+            log::error!("browser", "{}", text);
+        }
+        Err(e) => log::warning!("ws", "[{}] couldn't read message: {}", client.tag, e),
+    }
+    true
+}
+
+fn text(message: ServerMessage) -> Result<Message, ::serde_json::Error> {
+    Ok(Message::Text(::serde_json::to_string(&message)?))
+}
+
+/// Talk to one browser, until one of us hangs up,
+/// or it goes quiet for too long.
+async fn connection<S>(
+    stream: S,
+    peer: SocketAddr,
+    mut rx: ::tokio::sync::watch::Receiver<Option<BrowserAction>>,
+    status: &Status,
+) -> ::anyhow::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let ws_stream = ::tokio_tungstenite::accept_async(stream).await?;
+    let (mut sink, mut messages) = ws_stream.split();
+    let mut client = Client {
+        id: status.connected(peer),
+        tag: peer.to_string(),
+        last_heard: Instant::now(),
+    };
+    let result = talk(&mut sink, &mut messages, &mut rx, &mut client, status).await;
+    status.disconnected(client.id);
+    // They might be gone already, so this is only polite.
+    let _ = sink.send(Message::Close(None)).await;
+    result
+}
+
+async fn talk<Si, St>(
+    sink: &mut Si,
+    messages: &mut St,
+    rx: &mut ::tokio::sync::watch::Receiver<Option<BrowserAction>>,
+    client: &mut Client,
+    status: &Status,
+) -> ::anyhow::Result<()>
+where
+    Si: ::futures::Sink<Message, Error = ::tungstenite::Error> + Unpin,
+    St: ::futures::Stream<Item = Result<Message, ::tungstenite::Error>> + Unpin,
+{
+    sink.send(text(ServerMessage::Control(Control::Hello {
+        protocol: PROTOCOL,
+        capabilities: CAPABILITIES,
+    }))?)
+    .await?;
+    let mut pings = ::tokio::time::interval(PING_INTERVAL);
+    let mut ping = 0;
+    let mut count = 0;
+    loop {
+        ::tokio::select! {
+            action = rx.recv() => match action {
+                Some(Some(x)) => {
+                    log::debug!("ws", "[{}] session refresh count: {}", client.tag, count);
+                    count += 1;
+                    status.sent(client.id, &x);
+                    sink.send(text(ServerMessage::Action(x))?).await?
+                },
+                Some(None) => continue,
+                // If the fs watcher has closed,
+                // all connections should wind down.
+                None => break,
+            },
+            message = messages.next() => match message {
+                Some(Ok(Message::Text(x))) => {
+                    if !client_message(client, status, &x) {
+                        let outdated = Control::Outdated { protocol: PROTOCOL };
+                        sink.send(text(ServerMessage::Control(outdated))?).await?;
+                        break;
+                    }
+                }
+                Some(Ok(Message::Close(_))) | None => break,
+                // Pings and pongs at the WebSocket level count as hearing from them too.
+                Some(Ok(_)) => client.last_heard = Instant::now(),
+                Some(Err(e)) => Err(e)?,
+            },
+            _ = pings.tick() => {
+                if client.last_heard.elapsed() >= IDLE_TIMEOUT {
+                    log::debug!("ws", "[{}] went quiet, hanging up", client.tag);
+                    break;
+                }
+                ping += 1;
+                sink.send(text(ServerMessage::Control(Control::Ping(ping)))?).await?;
+            },
+        }
+    }
+    Ok(())
+}
+
+/// How long a connection gets to say what it wants.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// Longer than any request head we should ever get.
+const MAX_REQUEST_HEAD: usize = 16 * 1024;
+
+/// A stream, with what we've already read from it put back in front.
+/// We have to read a request to know whether it's for the WebSocket,
+/// but then the WebSocket handshake needs to read it again.
+struct Rewind<S> {
+    read: Vec<u8>,
+    pos: usize,
+    inner: S,
+}
+impl<S: AsyncRead + Unpin> AsyncRead for Rewind<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<::std::io::Result<usize>> {
+        if self.pos < self.read.len() {
+            let n = buf.len().min(self.read.len() - self.pos);
+            buf[..n].copy_from_slice(&self.read[self.pos..self.pos + n]);
+            self.pos += n;
+            return Poll::Ready(Ok(n));
+        }
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+impl<S: AsyncWrite + Unpin> AsyncWrite for Rewind<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<::std::io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<::std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<::std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Read up to the end of the request's headers.
+async fn request_head<S: AsyncRead + Unpin>(stream: &mut S) -> ::anyhow::Result<Vec<u8>> {
+    let mut read = Vec::new();
+    let mut chunk = [0; 4096];
+    while !read.windows(4).any(|x| x == b"\r\n\r\n") {
+        if read.len() > MAX_REQUEST_HEAD {
+            ::anyhow::bail!("request head too long");
+        }
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            ::anyhow::bail!("connection closed before a whole request");
+        }
+        read.extend_from_slice(&chunk[..n]);
+    }
+    Ok(read)
+}
+
+/// What a connection's asking for.
+#[derive(Debug, PartialEq, Eq)]
+enum Request {
+    WebSocket,
+    Page(String),
+}
+
+fn request(head: &[u8]) -> Request {
+    let head = String::from_utf8_lossy(head);
+    let mut lines = head.split("\r\n");
+    let path = lines
+        .next()
+        .and_then(|x| x.split(' ').nth(1))
+        .unwrap_or("/")
+        .to_string();
+    // Headers stop at the first empty line, and anything after's a body.
+    let upgrade = lines.take_while(|x| !x.is_empty()).any(|x| {
+        let mut parts = x.splitn(2, ':');
+        let name = parts.next().unwrap_or_default().trim();
+        let value = parts.next().unwrap_or_default();
+        // It's a list of protocols, maybe with versions, like `websocket, h2c`.
+        name.eq_ignore_ascii_case("upgrade")
+            && value.split(',').any(|protocol| {
+                let protocol = protocol.split('/').next().unwrap_or_default();
+                protocol.trim().eq_ignore_ascii_case("websocket")
+            })
+    });
+    if upgrade {
+        Request::WebSocket
+    } else {
+        Request::Page(path)
+    }
+}
+
+/// Anything that isn't the WebSocket gets the status page.
+async fn status_page<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    path: &str,
+    status: &Status,
+) -> ::std::io::Result<()> {
+    let (code, body) = match path {
+        "/" | "/status" => ("200 OK", status.render()),
+        _ => ("404 Not Found", "not found\n".to_string()),
+    };
+    let content_type = if code.starts_with("200") {
+        "text/html; charset=utf-8"
+    } else {
+        "text/plain"
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n\
+         Cache-Control: no-store\r\nConnection: close\r\n\r\n{}",
+        code,
+        content_type,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+/// Handle one connection, whatever it's for.
+async fn accept<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    peer: SocketAddr,
+    rx: ::tokio::sync::watch::Receiver<Option<BrowserAction>>,
+    status: &Status,
+) -> ::anyhow::Result<()> {
+    let head = ::tokio::time::timeout(REQUEST_TIMEOUT, request_head(&mut stream)).await??;
+    match request(&head) {
+        Request::WebSocket => {
+            let stream = Rewind {
+                read: head,
+                pos: 0,
+                inner: stream,
+            };
+            connection(stream, peer, rx, status).await
+        }
+        Request::Page(path) => Ok(status_page(&mut stream, &path, status).await?),
+    }
+}
 
 // TODO: Make this use Tokio instead.
 // It's a royal mess without it.
 #[::tokio::main]
-pub(crate) async fn start(opt: Opt, bins: Binaries) -> ::anyhow::Result<()> {
+pub(crate) async fn start(
+    opt: Opt,
+    config: Config,
+    bins: Binaries,
+    rules: Rules,
+    manifest: Manifest,
+) -> ::anyhow::Result<()> {
     // First, let's set up TLS.
-    let tls_config = ::std::sync::Arc::new(make_server_tls(&opt.project_root));
+    let tls_config = Arc::new(make_server_tls(&opt.project_root));
     let tls_acceptor = tokio_rustls::TlsAcceptor::from(tls_config);
 
-    copy(&opt.project_root)?;
-    let output: Option<process::Output> = elm(
-        &opt.project_root,
-        &bins.elm,
-        bins.terser.as_deref(),
-        false,
-        OutputMethod::Capture,
-    )?;
-    match output {
-        Some(x) => {
-            use ::std::io::Write;
-            ::std::io::stdout().write(&x.stdout)?;
-            ::std::io::stdout().write(&x.stderr)?;
-            ::std::io::stdout().flush()?;
-            ::anyhow::bail!("failed initial Elm build")
+    let status = Arc::new(Status::new());
+    let rules = Arc::new(rules);
+    let mut rebuilder = Rebuilder::new(config.clone(), rules.clone(), bins, manifest);
+    let started = Instant::now();
+    let failures = rebuilder.build()?;
+    if !failures.is_empty() {
+        for failure in &failures {
+            log::error!("elm", "{}", failure.report());
         }
-        None => (),
-    };
-
-    let mut builder = GlobSetBuilder::new();
-    builder.add(Glob::new("*.html")?);
-    builder.add(Glob::new("*.css")?);
-    builder.add(Glob::new("*.js")?);
-    let copy_matcher = builder.build()?;
-    let elm_matcher = Glob::new("*.elm")?.compile_matcher();
-    let rust_matcher = Glob::new("*.rs")?.compile_matcher();
-    let ignore_matcher = Glob::new("*#*")?.compile_matcher();
-    macro_rules! is_copy {
-        ($x:expr) => {
-            (copy_matcher.is_match($x) && !ignore_matcher.is_match($x))
-        };
+        ::anyhow::bail!("failed initial Elm build")
     }
-    macro_rules! is_elm {
-        ($x:expr) => {
-            (elm_matcher.is_match($x) && !ignore_matcher.is_match($x))
-        };
-    }
-    macro_rules! is_rust {
-        ($x:expr) => {
-            (rust_matcher.is_match($x) && !ignore_matcher.is_match($x))
-        };
-    }
-    let mopt = opt.clone();
+    status.built(started.elapsed(), None);
+    protocol::write_reload_config(&config.build.output_dir, Some(config.dev.port))?;
 
-    let mut listener = ::tokio::net::TcpListener::bind("0.0.0.0:9000")
-        .await
-        .expect("failed to bind tcp port");
+    let mut listener =
+        ::tokio::net::TcpListener::bind((config.dev.address.as_str(), config.dev.port))
+            .await
+            .expect("failed to bind tcp port");
 
     let (tx, rx) = ::tokio::sync::watch::channel(None::<BrowserAction>);
+    // The supervisor talks to browsers too.
+    let tx = Arc::new(tx);
+    let browser = tx.clone();
     let (stx, srx) = ::tokio::sync::watch::channel(None::<ServerAction>);
-    let (wtx, wrx) = ::std::sync::mpsc::channel();
-    let mut watcher: RecommendedWatcher = Watcher::new(wtx, ::std::time::Duration::from_secs(0))?;
-    watcher.watch(opt.project_root.join("src"), RecursiveMode::Recursive)?;
-    thread::spawn(move || {
-        enum ErrorState {
-            Elm(process::Output),
-            None,
-        }
-        let mut error_state = ErrorState::None;
-        loop {
-            match wrx.recv() {
-                Ok(event) => {
-                    println!("notify event: {:?}", event);
-                    let path = match event_path(&event) {
-                        Some(x) => x,
-                        None => continue,
-                    };
-                    let mut refresh_copies = false;
-                    let mut refresh_elm = false;
-                    let mut refresh_rust = false;
-                    if path.is_dir() {
-                        for entry in WalkDir::new(path) {
-                            let entry = match entry {
-                                Ok(x) => x,
-                                Err(_) => continue,
-                            };
-                            if is_copy!(entry.path()) {
-                                refresh_copies = true;
-                            }
-                            if is_elm!(entry.path()) {
-                                refresh_elm = true;
-                            }
-                            if is_rust!(entry.path()) {
-                                refresh_rust = true;
-                            }
-                            if refresh_copies && refresh_elm {
-                                break;
-                            }
-                        }
-                    } else {
-                        if is_copy!(path) {
-                            refresh_copies = true;
-                        }
-                        if is_elm!(path) {
-                            refresh_elm = true;
-                        }
-                        if is_rust!(path) {
-                            refresh_rust = true;
-                        }
-                    }
-                    if refresh_copies {
-                        let _ = copy(&mopt.project_root);
-                    }
-                    if refresh_elm {
-                        let output: Option<process::Output> = elm(
-                            &mopt.project_root,
-                            &bins.elm,
-                            bins.terser.as_deref(),
-                            false,
-                            OutputMethod::Capture,
-                        )
-                        .unwrap();
-                        match output {
-                            Some(x) => error_state = ErrorState::Elm(x),
-                            None => error_state = ErrorState::None,
-                        };
-                    }
-                    if refresh_copies || refresh_elm {
-                        match error_state {
-                            ErrorState::Elm(ref x) => tx
-                                .broadcast(Some(BrowserAction::DisplayError(
-                                    String::from_utf8_lossy(&x.stderr).into_owned(),
-                                )))
-                                .expect("channel closed"),
-                            ErrorState::None => tx
-                                .broadcast(Some(BrowserAction::RefreshPage(RefreshToken::new())))
-                                .expect("channel closed"),
-                        }
-                    }
-                    if refresh_rust {
-                        stx.broadcast(Some(ServerAction::Reload(RefreshToken::new())))
-                            .expect("channel closed");
+    let (_watcher, wrx) = watch::watch(&config)?;
+    let wstatus = status.clone();
+    thread::spawn(move || loop {
+        match wrx.recv() {
+            Ok(event) => {
+                log::debug!("watch", "{:?}", event);
+                let mut changes = Changes::default();
+                changes.add(&rules, &event);
+                if changes.site() {
+                    let rebuilt = rebuilder.rebuild(changes);
+                    watch::report(&rebuilt);
+                    wstatus.built(rebuilt.time, rebuilt.error.as_deref());
+                    match rebuilt.error {
+                        Some(x) => tx
+                            .broadcast(Some(BrowserAction::DisplayError(x)))
+                            .expect("channel closed"),
+                        None => tx
+                            .broadcast(Some(BrowserAction::RefreshPage(RefreshToken::new())))
+                            .expect("channel closed"),
                     }
                 }
-                Err(e) => eprintln!("watch error: {}", e),
+                if changes.rust {
+                    stx.broadcast(Some(ServerAction::Reload(RefreshToken::new())))
+                        .expect("channel closed");
+                }
             }
+            Err(e) => log::error!("watch", "{}", e),
         }
     });
-    let project_root = opt.project_root.clone();
-
-    ::tokio::spawn(rocket_main(project_root, srx));
+    if let Target::Dev { faults: true, .. } = opt.target {
+        let faults = config.dev.faults.clone();
+        let listener =
+            ::tokio::net::TcpListener::bind((config.dev.address.as_str(), faults.port)).await?;
+        let upstream =
+            SocketAddr::from(([127, 0, 0, 1], supervisor::rocket_port(&opt.project_root)));
+        log::info!(
+            "faults",
+            "proxying port {} to the server on {}, with {}",
+            faults.port,
+            upstream.port(),
+            faults::describe(&faults)
+        );
+        ::tokio::spawn(faults::proxy(listener, upstream, faults));
+    }
+    let supervisor = ::tokio::spawn(supervisor::supervise(
+        opt.project_root.clone(),
+        srx,
+        browser,
+        status.clone(),
+    ));
+    log::info!(
+        "dev",
+        "status page at https://{}:{}/",
+        config.dev.address,
+        config.dev.port
+    );
 
     // Give each connection its own task.
-    while let Ok((stream, _)) = listener.accept().await {
-        let acceptor = tls_acceptor.clone();
-        // Give each spawned task its own receiver.
-        let mut rx = rx.clone();
-        ::tokio::spawn(async move {
-            ::foretry::async_try! { _, ::anyhow::Error | {
-                let stream = acceptor.accept(stream).await?;
-                let mut ws_stream = ::tokio_tungstenite::accept_async(stream).await?;
-                // If the fs watcher has closed,
-                // all connections should wind down.
-                let mut count = 0;
-                while let Some(action) = rx.recv().await {
-                    match action {
-                        Some(x) => {
-                            println!("session refresh count: {}", count);
-                            count += 1;
-                            ws_stream.send(Message::Text(::serde_json::to_string(&x)?)).await?
-                        },
-                        None => continue,
-                    }
-                }
-            } catch (e) {
-                eprintln!("{:?}", e);
-            }}
-        });
+    let accept = async {
+        while let Ok((stream, peer)) = listener.accept().await {
+            let acceptor = tls_acceptor.clone();
+            // Give each spawned task its own receiver.
+            let rx = rx.clone();
+            let status = status.clone();
+            ::tokio::spawn(async move {
+                ::foretry::async_try! { _, ::anyhow::Error | {
+                    let stream = acceptor.accept(stream).await?;
+                    accept(stream, peer, rx, &status).await?;
+                } catch (e) {
+                    log::warning!("ws", "{:#}", e);
+                }}
+            });
+        }
+    };
+    // The supervisor finishes on Ctrl-C, once the server's stopped.
+    ::tokio::select! {
+        _ = accept => (),
+        _ = supervisor => (),
     }
     Ok(())
 }
 
-/// Task managing the main server process.
-async fn rocket_main(
-    project_root: ::std::path::PathBuf,
-    mut srx: ::tokio::sync::watch::Receiver<Option<ServerAction>>,
-) {
-    let mut rocket = spawn_rocket(&project_root);
-    while let Some(action) = srx.recv().await {
-        match action {
-            Some(ServerAction::Reload(_)) => {
-                let _ = rocket.kill();
-                rocket = spawn_rocket(&project_root);
-            },
-            None => continue,
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Hands out what it's given a piece at a time, like a slow client.
+    struct Pieces(Vec<Vec<u8>>);
+    impl AsyncRead for Pieces {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<::std::io::Result<usize>> {
+            if self.0.is_empty() {
+                return Poll::Ready(Ok(0));
+            }
+            let piece = &mut self.0[0];
+            let n = buf.len().min(piece.len());
+            buf[..n].copy_from_slice(&piece[..n]);
+            piece.drain(..n);
+            if piece.is_empty() {
+                self.0.remove(0);
+            }
+            Poll::Ready(Ok(n))
         }
     }
 
-}
+    fn pieces(pieces: &[&str]) -> Pieces {
+        Pieces(pieces.iter().map(|x| x.as_bytes().to_vec()).collect())
+    }
 
-fn spawn_rocket(project_root: &Path) -> ::std::process::Child {
-    // Since we're not waiting on this anymore, we don't need to spawn it in another thread.
-    ::std::process::Command::new("cargo")
-        .arg("run")
-        .arg("--manifest-path")
-        .arg(project_root.join("Cargo.toml"))
-        .spawn()
-        .expect("failed to spawn Rocket main")
+    #[test]
+    fn websocket_upgrades_are_spotted() {
+        let upgrade = |headers: &str| {
+            request(
+                format!("GET /ws HTTP/1.1\r\nHost: localhost\r\n{}\r\n\r\n", headers).as_bytes(),
+            )
+        };
+        assert_eq!(
+            upgrade("Upgrade: websocket\r\nConnection: Upgrade"),
+            Request::WebSocket
+        );
+        assert_eq!(upgrade("UPGRADE: WebSocket"), Request::WebSocket);
+        assert_eq!(upgrade("upgrade:websocket"), Request::WebSocket);
+        assert_eq!(upgrade("Upgrade: h2c, websocket"), Request::WebSocket);
+        assert_eq!(upgrade("Upgrade: websocket/13 , h2c"), Request::WebSocket);
+        assert_eq!(upgrade("Upgrade: h2c"), Request::Page("/ws".into()));
+        assert_eq!(upgrade("X-Upgrade: websocket"), Request::Page("/ws".into()));
+        assert_eq!(upgrade("Upgrade: websockets"), Request::Page("/ws".into()));
+    }
+
+    #[test]
+    fn pages_are_everything_else() {
+        assert_eq!(
+            request(b"GET /status HTTP/1.1\r\nHost: localhost\r\n\r\n"),
+            Request::Page("/status".into())
+        );
+        // What's after the headers isn't a header.
+        assert_eq!(
+            request(b"POST / HTTP/1.1\r\nContent-Length: 18\r\n\r\nUpgrade: websocket"),
+            Request::Page("/".into())
+        );
+        assert_eq!(request(b"\r\n\r\n"), Request::Page("/".into()));
+    }
+
+    #[::tokio::test]
+    async fn request_heads_can_come_in_pieces() {
+        let mut stream = pieces(&[
+            "GET / HTTP/1.1\r",
+            "\nUpgrade: websocket\r\n",
+            "\r",
+            "\nhello",
+        ]);
+        let head = request_head(&mut stream).await.unwrap();
+        assert_eq!(head, b"GET / HTTP/1.1\r\nUpgrade: websocket\r\n\r\nhello");
+        assert_eq!(request(&head), Request::WebSocket);
+
+        let mut stream = pieces(&["GET / HTTP/1.1\r\n"]);
+        assert!(request_head(&mut stream).await.is_err());
+        let mut stream = Pieces(vec![vec![b'a'; MAX_REQUEST_HEAD + 4096]]);
+        assert!(request_head(&mut stream).await.is_err());
+    }
+
+    #[::tokio::test]
+    async fn rewind_reads_the_head_again_then_the_rest() {
+        let mut stream = pieces(&["GET / HTTP/1.1\r\n\r\nfirst", " second"]);
+        let head = request_head(&mut stream).await.unwrap();
+        let mut rewound = Rewind {
+            read: head,
+            pos: 0,
+            inner: stream,
+        };
+        let mut all = String::new();
+        rewound.read_to_string(&mut all).await.unwrap();
+        assert_eq!(all, "GET / HTTP/1.1\r\n\r\nfirst second");
+    }
 }
@@ -0,0 +1,271 @@
+//! What the dev server knows about itself, for the status page.
+//! That's who's connected and what they were last sent,
+//! how the last few builds went, and what the Rocket server is up to.
+//! Everything that changes any of that tells [`Status`],
+//! and the status page is rendered from it on every request.
+use crate::log;
+use crate::protocol::BrowserAction;
+use ::std::collections::{BTreeMap, VecDeque};
+use ::std::fmt::Write as _;
+use ::std::net::SocketAddr;
+use ::std::sync::Mutex;
+use ::std::time::{Duration, SystemTime};
+
+/// How many builds to remember.
+const BUILD_HISTORY: usize = 20;
+
+struct Session {
+    address: SocketAddr,
+    /// Until they say hello, we don't know these.
+    tag: Option<String>,
+    user_agent: Option<String>,
+    page: Option<String>,
+    connected: SystemTime,
+    /// What we last sent, and when.
+    last_action: Option<(&'static str, SystemTime)>,
+    last_token: Option<u64>,
+    sent: u64,
+}
+
+struct Build {
+    at: SystemTime,
+    time: Duration,
+    error: Option<String>,
+}
+
+/// What the Rocket server's doing.
+#[derive(Debug, Clone)]
+pub(crate) enum Rocket {
+    Building,
+    /// Started, but not listening yet.
+    Starting {
+        pid: u32,
+    },
+    Up {
+        pid: u32,
+        port: u16,
+    },
+    /// Still up, with a new one on the way to take over.
+    /// It doesn't have a PID while it's building.
+    Replacing {
+        pid: u32,
+        port: u16,
+        next: Option<u32>,
+    },
+    Down(String),
+    Restarting,
+}
+
+struct Inner {
+    next_session: u64,
+    sessions: BTreeMap<u64, Session>,
+    builds: VecDeque<Build>,
+    rocket: (Rocket, SystemTime),
+    restarts: u64,
+}
+
+pub(crate) struct Status {
+    started: SystemTime,
+    inner: Mutex<Inner>,
+}
+impl Status {
+    pub(crate) fn new() -> Self {
+        Self {
+            started: SystemTime::now(),
+            inner: Mutex::new(Inner {
+                next_session: 0,
+                sessions: BTreeMap::new(),
+                builds: VecDeque::new(),
+                rocket: (Rocket::Restarting, SystemTime::now()),
+                restarts: 0,
+            }),
+        }
+    }
+
+    fn lock(&self) -> ::std::sync::MutexGuard<'_, Inner> {
+        // Nothing in here can be left half done, so a panic elsewhere is fine.
+        self.inner.lock().unwrap_or_else(|x| x.into_inner())
+    }
+
+    /// A new browser connected. Returns its ID, for the rest of these.
+    pub(crate) fn connected(&self, address: SocketAddr) -> u64 {
+        let mut inner = self.lock();
+        let id = inner.next_session;
+        inner.next_session += 1;
+        inner.sessions.insert(
+            id,
+            Session {
+                address,
+                tag: None,
+                user_agent: None,
+                page: None,
+                connected: SystemTime::now(),
+                last_action: None,
+                last_token: None,
+                sent: 0,
+            },
+        );
+        id
+    }
+
+    pub(crate) fn hello(&self, id: u64, tag: &str, user_agent: &str, page: &str) {
+        if let Some(session) = self.lock().sessions.get_mut(&id) {
+            session.tag = Some(tag.into());
+            session.user_agent = Some(user_agent.into());
+            session.page = Some(page.into());
+        }
+    }
+
+    pub(crate) fn sent(&self, id: u64, action: &BrowserAction) {
+        if let Some(session) = self.lock().sessions.get_mut(&id) {
+            session.last_action = Some((action.kind(), SystemTime::now()));
+            if let Some(token) = action.token() {
+                session.last_token = Some(token);
+            }
+            session.sent += 1;
+        }
+    }
+
+    pub(crate) fn disconnected(&self, id: u64) {
+        self.lock().sessions.remove(&id);
+    }
+
+    pub(crate) fn built(&self, time: Duration, error: Option<&str>) {
+        let mut inner = self.lock();
+        if inner.builds.len() == BUILD_HISTORY {
+            inner.builds.pop_front();
+        }
+        inner.builds.push_back(Build {
+            at: SystemTime::now(),
+            time,
+            error: error.map(String::from),
+        });
+    }
+
+    pub(crate) fn rocket(&self, state: Rocket) {
+        let mut inner = self.lock();
+        if let Rocket::Restarting = state {
+            inner.restarts += 1;
+        }
+        inner.rocket = (state, SystemTime::now());
+    }
+
+    /// The status page.
+    pub(crate) fn render(&self) -> String {
+        let inner = self.lock();
+        let now = SystemTime::now();
+        let mut body = String::new();
+
+        let (ref rocket, since) = inner.rocket;
+        let rocket = match rocket {
+            Rocket::Building => "building".into(),
+            Rocket::Starting { pid } => format!("starting (pid {})", pid),
+            Rocket::Up { pid, port } => format!("up on port {} (pid {})", port, pid),
+            Rocket::Replacing {
+                pid,
+                port,
+                next: None,
+            } => format!("up on port {} (pid {}), with a new one building", port, pid),
+            Rocket::Replacing {
+                pid,
+                port,
+                next: Some(next),
+            } => format!(
+                "up on port {} (pid {}), with pid {} starting to take over",
+                port, pid, next
+            ),
+            Rocket::Down(why) => format!("down: {}", why),
+            Rocket::Restarting => "restarting".into(),
+        };
+        let _ = writeln!(
+            body,
+            "<h2>Rocket</h2>\n<p>{}, since {}. Restarted {} times.</p>",
+            escape(&rocket),
+            when(since, now),
+            inner.restarts
+        );
+
+        let _ = writeln!(
+            body,
+            "<h2>Sessions</h2>\n<table>\n<tr><th>Session</th><th>Address</th>\
+             <th>Page</th><th>User agent</th><th>Connected</th>\
+             <th>Last action</th><th>Last token</th><th>Sent</th></tr>"
+        );
+        if inner.sessions.is_empty() {
+            body.push_str("<tr><td colspan=\"8\">Nobody's connected.</td></tr>\n");
+        }
+        for session in inner.sessions.values() {
+            let last_action = match session.last_action {
+                Some((kind, at)) => format!("{}, {}", kind, when(at, now)),
+                None => "none".into(),
+            };
+            let _ = writeln!(
+                body,
+                "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td>\
+                 <td>{}</td><td>{}</td><td>{}</td></tr>",
+                escape(session.tag.as_deref().unwrap_or("(no hello yet)")),
+                session.address,
+                escape(session.page.as_deref().unwrap_or("")),
+                escape(session.user_agent.as_deref().unwrap_or("")),
+                when(session.connected, now),
+                escape(&last_action),
+                session
+                    .last_token
+                    .map(|x| x.to_string())
+                    .unwrap_or_default(),
+                session.sent
+            );
+        }
+        body.push_str("</table>\n");
+
+        body.push_str(
+            "<h2>Builds</h2>\n<table>\n<tr><th>When</th><th>Took</th><th>Result</th></tr>\n",
+        );
+        // Newest first.
+        for build in inner.builds.iter().rev() {
+            let result = match build.error {
+                None => "ok".to_string(),
+                Some(ref error) => format!("<pre>{}</pre>", escape(error)),
+            };
+            let _ = writeln!(
+                body,
+                "<tr><td>{}</td><td>{:.2}s</td><td>{}</td></tr>",
+                when(build.at, now),
+                build.time.as_secs_f64(),
+                result
+            );
+        }
+        body.push_str("</table>\n");
+
+        format!(
+            "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+             <meta http-equiv=\"refresh\" content=\"2\">\n\
+             <title>fileshare-build dev</title>\n\
+             <style>body {{ font-family: sans-serif; }} \
+             table {{ border-collapse: collapse; }} \
+             td, th {{ border: 1px solid #ccc; padding: 0.2em 0.5em; text-align: left; vertical-align: top; }} \
+             pre {{ margin: 0; }}</style>\n\
+             </head>\n<body>\n<h1>fileshare-build dev</h1>\n<p>Up since {}.</p>\n{}</body>\n</html>\n",
+            when(self.started, now),
+            body
+        )
+    }
+}
+
+/// Like `14:03:27.512 (12s ago)`.
+fn when(at: SystemTime, now: SystemTime) -> String {
+    let ago = now.duration_since(at).unwrap_or_default().as_secs();
+    let ago = match ago {
+        x if x < 60 => format!("{}s", x),
+        x if x < 3600 => format!("{}m", x / 60),
+        x => format!("{}h", x / 3600),
+    };
+    format!("{} ({} ago)", log::clock(at), ago)
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
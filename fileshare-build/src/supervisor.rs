@@ -0,0 +1,728 @@
+//! Keeping the Rocket server running in dev mode.
+//! It's built with `cargo build`, which says where the executable went,
+//! and that's run directly, so the PID we have is the server's, not `cargo`'s.
+//! Both run in their own process groups,
+//! so stopping them takes out anything they started too.
+//! Stopping is polite first, with `SIGTERM`, and less polite after [`STOP_TIMEOUT`].
+//! If the server dies on its own, it gets restarted, waiting longer each time
+//! it dies quickly, so a server that crashes on startup doesn't spin.
+//! Browsers hear about it going down and coming back up.
+//!
+//! We own the server's listening socket, and hand it to each server
+//! the way systemd socket activation does, with `LISTEN_FDS`.
+//! When Rust sources change, the new server builds and starts alongside the old one,
+//! which keeps serving. Once the new one says it's ready, through `NOTIFY_SOCKET`,
+//! the old one gets `SIGTERM`, and [`DRAIN_TIMEOUT`] to finish what it's doing.
+//! Browsers only hear the new one's up once the old one says it's stopping,
+//! so a refresh doesn't land on old code over a kept-alive connection.
+//! If the new one doesn't build, the old one just keeps going.
+//!
+//! Handing over sockets, process groups and signals are all unix things.
+//! Elsewhere, each server binds its own socket, so the old one has to stop
+//! before the new one starts, it's ready once its port takes connections,
+//! and stopping it just kills it.
+//!
+//! Output gets labelled `cargo` while building, and `rocket` after that.
+use crate::log;
+use crate::protocol::{BrowserAction, RefreshToken};
+use crate::server::ServerAction;
+use crate::status::{self, Status};
+use ::serde::Deserialize;
+use ::std::io;
+#[cfg(unix)]
+use ::std::os::unix::io::{AsRawFd, RawFd};
+#[cfg(unix)]
+use ::std::os::unix::process::CommandExt;
+use ::std::path::{Path, PathBuf};
+use ::std::process::{Command, ExitStatus, Stdio};
+use ::std::sync::Arc;
+use ::std::time::{Duration, Instant};
+use ::tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
+#[cfg(unix)]
+use ::tokio::net::UnixDatagram;
+use ::tokio::process::Child;
+use ::tokio::sync::watch;
+use ::tokio::task::JoinHandle;
+
+/// How long the server gets to stop after `SIGTERM`, before `SIGKILL`.
+const STOP_TIMEOUT: Duration = Duration::from_secs(5);
+/// The first wait before restarting a crashed server.
+const BACKOFF_MIN: Duration = Duration::from_millis(500);
+const BACKOFF_MAX: Duration = Duration::from_secs(30);
+/// A server that ran at least this long before crashing
+/// gets restarted right away, rather than backing off further.
+const STABLE: Duration = Duration::from_secs(30);
+/// How long an old server gets to finish its requests, once a new one's taken over.
+/// Long enough for a big upload, hopefully.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(120);
+/// How long to wait for an old server to say it's stopping,
+/// before telling browsers about the new one anyway.
+const STOPPING_TIMEOUT: Duration = Duration::from_secs(1);
+/// Where inherited sockets go, as in `sd_listen_fds`.
+#[cfg(unix)]
+const LISTEN_FDS_START: RawFd = 3;
+/// How often to check whether a server's port takes connections yet,
+/// where it can't tell us itself.
+#[cfg(not(unix))]
+const READY_POLL: Duration = Duration::from_millis(100);
+
+/// Just enough of `Rocket.toml` to know where the dev server listens.
+#[derive(Deserialize, Default)]
+struct RocketToml {
+    #[serde(default)]
+    development: RocketEnv,
+}
+#[derive(Deserialize, Default)]
+struct RocketEnv {
+    address: Option<String>,
+    port: Option<u16>,
+}
+
+fn rocket_env(project_root: &Path) -> RocketEnv {
+    ::std::fs::read(project_root.join("Rocket.toml"))
+        .ok()
+        .and_then(|x| ::toml::from_slice::<RocketToml>(&x).ok())
+        .map(|x| x.development)
+        .unwrap_or_default()
+}
+
+/// Where the server listens.
+/// Rocket's dev default is `localhost`, and `ROCKET_ADDRESS` beats everything.
+fn rocket_address(project_root: &Path) -> String {
+    ::std::env::var("ROCKET_ADDRESS")
+        .ok()
+        .or_else(|| rocket_env(project_root).address)
+        .unwrap_or_else(|| "localhost".into())
+}
+
+/// The port the server listens on.
+/// Rocket's dev default is 8000, and `ROCKET_PORT` beats everything.
+pub(crate) fn rocket_port(project_root: &Path) -> u16 {
+    if let Some(port) = ::std::env::var("ROCKET_PORT")
+        .ok()
+        .and_then(|x| x.parse().ok())
+    {
+        return port;
+    }
+    rocket_env(project_root).port.unwrap_or(8000)
+}
+
+/// The socket every server listens on, which outlives each of them.
+#[cfg(unix)]
+struct Socket {
+    listener: ::std::net::TcpListener,
+}
+#[cfg(unix)]
+impl Socket {
+    fn bind(address: &str, port: u16) -> io::Result<Self> {
+        Ok(Self {
+            listener: ::std::net::TcpListener::bind((address, port))?,
+        })
+    }
+
+    /// Hand the socket to `c`, the way systemd socket activation does.
+    fn pass(&self, c: &mut Command) {
+        let listener = self.listener.as_raw_fd();
+        c.env("LISTEN_FDS", "1")
+            // We'd need its PID before it starts to set this, and it's optional.
+            .env_remove("LISTEN_PID");
+        // Safe, since these are all fine to call between `fork` and `exec`.
+        unsafe {
+            c.pre_exec(move || {
+                // `dup2` leaves the copy open across `exec`,
+                // unless it's already where it should be.
+                if listener == LISTEN_FDS_START {
+                    if ::libc::fcntl(listener, ::libc::F_SETFD, 0) == -1 {
+                        return Err(io::Error::last_os_error());
+                    }
+                } else if ::libc::dup2(listener, LISTEN_FDS_START) == -1 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+    }
+}
+
+/// Nothing, since each server binds its own.
+#[cfg(not(unix))]
+struct Socket {
+    address: String,
+    port: u16,
+}
+#[cfg(not(unix))]
+impl Socket {
+    fn bind(address: &str, port: u16) -> io::Result<Self> {
+        Ok(Self {
+            address: address.into(),
+            port,
+        })
+    }
+
+    fn pass(&self, _c: &mut Command) {}
+}
+
+/// Where a server says it's ready, like systemd's `NOTIFY_SOCKET`.
+/// Each server gets its own, so there's no mixing up who's ready.
+#[cfg(unix)]
+struct Notify {
+    socket: UnixDatagram,
+    path: PathBuf,
+}
+#[cfg(unix)]
+impl Notify {
+    fn new(n: u64, _socket: &Socket) -> io::Result<Self> {
+        let path = ::std::env::temp_dir().join(format!(
+            "fileshare-build-{}-{}.sock",
+            ::std::process::id(),
+            n
+        ));
+        // Left over from a crash, probably.
+        let _ = ::std::fs::remove_file(&path);
+        Ok(Self {
+            socket: UnixDatagram::bind(&path)?,
+            path,
+        })
+    }
+
+    fn pass(&self, c: &mut Command) {
+        c.env("NOTIFY_SOCKET", &self.path);
+    }
+
+    /// Wait for `state`, like `READY=1`.
+    async fn wait(&mut self, state: &str) -> io::Result<()> {
+        let mut buf = [0; 1024];
+        loop {
+            let n = self.socket.recv(&mut buf).await?;
+            if String::from_utf8_lossy(&buf[..n])
+                .lines()
+                .any(|x| x == state)
+            {
+                return Ok(());
+            }
+        }
+    }
+}
+#[cfg(unix)]
+impl Drop for Notify {
+    fn drop(&mut self) {
+        let _ = ::std::fs::remove_file(&self.path);
+    }
+}
+
+/// A server's ready once its port takes connections.
+/// There's no hearing it's stopping, but it's stopped before the next one starts.
+#[cfg(not(unix))]
+struct Notify {
+    address: String,
+    port: u16,
+}
+#[cfg(not(unix))]
+impl Notify {
+    fn new(_n: u64, socket: &Socket) -> io::Result<Self> {
+        // Whatever it's listening on, it's listening on loopback.
+        let address = match socket.address.as_str() {
+            "0.0.0.0" | "::" => "localhost".into(),
+            x => x.into(),
+        };
+        Ok(Self {
+            address,
+            port: socket.port,
+        })
+    }
+
+    fn pass(&self, _c: &mut Command) {}
+
+    async fn wait(&mut self, state: &str) -> io::Result<()> {
+        if state != "READY=1" {
+            return Ok(());
+        }
+        let address = (self.address.as_str(), self.port);
+        while ::tokio::net::TcpStream::connect(address).await.is_err() {
+            ::tokio::time::delay_for(READY_POLL).await;
+        }
+        Ok(())
+    }
+}
+
+/// A new server, on its way.
+enum Starting {
+    /// `cargo build`, and what it says it built.
+    Building {
+        child: Child,
+        artifact: JoinHandle<Option<PathBuf>>,
+    },
+    /// Started, but not ready yet.
+    Running { child: Child, notify: Notify },
+}
+impl Starting {
+    fn child(&mut self) -> &mut Child {
+        match self {
+            Self::Building { child, .. } | Self::Running { child, .. } => child,
+        }
+    }
+}
+
+/// The server that's taking connections.
+struct Serving {
+    child: Child,
+    notify: Notify,
+    since: Instant,
+}
+
+/// Start building the server.
+/// The returned task finishes with where the executable went, once `cargo` says.
+fn build(project_root: &Path, name: String) -> io::Result<(Child, JoinHandle<Option<PathBuf>>)> {
+    let mut c = crate::cargo_command(project_root, false, false, "build");
+    c.arg(crate::ARTIFACT_FORMAT)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    // Its own group, so stopping it stops `rustc` too.
+    own_group(&mut c);
+    let mut child = ::tokio::process::Command::from(c).spawn()?;
+    if let Some(stderr) = child.stderr.take() {
+        ::tokio::spawn(forward(stderr, true, "cargo"));
+    }
+    let stdout = child.stdout.take().expect("stdout was piped");
+    let artifact = ::tokio::spawn(async move {
+        let mut lines = BufReader::new(stdout).lines();
+        let mut executable = None;
+        while let Ok(Some(line)) = lines.next_line().await {
+            if let Some(x) = crate::artifact_executable(&line, &name) {
+                executable = Some(x);
+            }
+        }
+        executable
+    });
+    Ok((child, artifact))
+}
+
+fn spawn(
+    server: &Path,
+    project_root: &Path,
+    socket: &Socket,
+    notify: &Notify,
+) -> io::Result<Child> {
+    let mut c = Command::new(server);
+    // Where `Rocket.toml` and the certs are.
+    c.current_dir(project_root)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    socket.pass(&mut c);
+    notify.pass(&mut c);
+    // Its own group, so we can signal everything under it at once.
+    // This also means Ctrl-C doesn't reach it, so we stop it ourselves.
+    own_group(&mut c);
+    let mut child = ::tokio::process::Command::from(c).spawn()?;
+    if let Some(stdout) = child.stdout.take() {
+        ::tokio::spawn(forward(stdout, false, "rocket"));
+    }
+    if let Some(stderr) = child.stderr.take() {
+        ::tokio::spawn(forward(stderr, true, "rocket"));
+    }
+    Ok(child)
+}
+
+/// Pass on a child's output, labelled.
+async fn forward<R: AsyncRead + Unpin>(output: R, stderr: bool, source: &'static str) {
+    let mut lines = BufReader::new(output).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        log::child_line(source, stderr, &line);
+    }
+}
+
+#[cfg(unix)]
+fn own_group(c: &mut Command) {
+    c.process_group(0);
+}
+
+#[cfg(not(unix))]
+fn own_group(_c: &mut Command) {}
+
+/// Send `signal` to everything in `child`'s process group.
+/// Anything that's already gone is fine.
+#[cfg(unix)]
+fn signal(child: &Child, signal: ::libc::c_int) {
+    // The group's ID is the ID of the process that started it.
+    let group = child.id() as ::libc::pid_t;
+    unsafe {
+        ::libc::kill(-group, signal);
+    }
+}
+
+/// Ask `child` to stop.
+#[cfg(unix)]
+fn terminate(child: &mut Child) {
+    signal(child, ::libc::SIGTERM);
+}
+
+/// There's no asking, so this kills it.
+#[cfg(not(unix))]
+fn terminate(child: &mut Child) {
+    kill(child);
+}
+
+/// Stop `child` and everything it started, now.
+#[cfg(unix)]
+fn kill(child: &mut Child) {
+    signal(child, ::libc::SIGKILL);
+}
+
+/// Stop `child`, now. Anything it started is on its own.
+#[cfg(not(unix))]
+fn kill(child: &mut Child) {
+    // Already gone is fine.
+    let _ = child.kill();
+}
+
+/// Stop the server and everything it started, and reap it.
+async fn stop(child: &mut Child) -> io::Result<ExitStatus> {
+    terminate(child);
+    let status = match ::tokio::time::timeout(STOP_TIMEOUT, &mut *child).await {
+        Ok(status) => status,
+        Err(_) => {
+            log::warning!(
+                "dev",
+                "server didn't stop within {}s, killing it",
+                STOP_TIMEOUT.as_secs()
+            );
+            kill(child);
+            (&mut *child).await
+        }
+    };
+    // Whatever `cargo` started might outlive it.
+    kill(child);
+    status
+}
+
+/// Let an old server that's had `SIGTERM` finish what it's doing, and stop.
+/// It gets killed after [`DRAIN_TIMEOUT`], or when we're shutting down.
+async fn drain(mut child: Child, mut shutdown: watch::Receiver<bool>) {
+    let pid = child.id();
+    let shutdown = async { while let Some(false) = shutdown.recv().await {} };
+    let finished = ::tokio::select! {
+        _ = &mut child => true,
+        _ = ::tokio::time::delay_for(DRAIN_TIMEOUT) => {
+            log::warning!(
+                "dev",
+                "old server (pid {}) didn't finish within {}s, killing it",
+                pid,
+                DRAIN_TIMEOUT.as_secs()
+            );
+            false
+        }
+        _ = shutdown => false,
+    };
+    kill(&mut child);
+    if !finished {
+        let _ = (&mut child).await;
+    }
+    log::debug!("dev", "old server (pid {}) is gone", pid);
+}
+
+/// Wait for `child` to exit, or forever, if there isn't one.
+async fn exited(child: Option<&mut Child>) -> io::Result<ExitStatus> {
+    match child {
+        Some(x) => x.await,
+        None => ::futures::future::pending().await,
+    }
+}
+
+async fn ready(notify: Option<&mut Notify>) -> io::Result<()> {
+    match notify {
+        Some(x) => x.wait("READY=1").await,
+        None => ::futures::future::pending().await,
+    }
+}
+
+enum Event {
+    /// The server that was taking connections stopped on its own.
+    ServingExited(io::Result<ExitStatus>),
+    /// The new server stopped before it was ready.
+    StartingExited(io::Result<ExitStatus>),
+    Ready(io::Result<()>),
+    Reload,
+    Retry,
+    Shutdown,
+}
+
+/// Run the server until Ctrl-C, replacing it whenever
+/// `srx` says to, or it stops on its own.
+pub(crate) async fn supervise(
+    project_root: PathBuf,
+    mut srx: watch::Receiver<Option<ServerAction>>,
+    browser: Arc<watch::Sender<Option<BrowserAction>>>,
+    status: Arc<Status>,
+) {
+    let tell = |action| {
+        // Nobody listening is fine.
+        let _ = browser.broadcast(Some(action));
+    };
+    let address = rocket_address(&project_root);
+    let port = rocket_port(&project_root);
+    let socket = match Socket::bind(&address, port) {
+        Ok(x) => x,
+        Err(e) => {
+            let why = format!("couldn't listen on {}:{}: {}", address, port, e);
+            log::error!("dev", "{}", why);
+            status.rocket(status::Rocket::Down(why.clone()));
+            tell(BrowserAction::BackendDown(why));
+            return;
+        }
+    };
+    let name = match crate::package::app_package(&project_root) {
+        Ok(x) => x.name,
+        Err(e) => {
+            let why = format!("couldn't find the server's package: {:#}", e);
+            log::error!("dev", "{}", why);
+            status.rocket(status::Rocket::Down(why.clone()));
+            tell(BrowserAction::BackendDown(why));
+            return;
+        }
+    };
+    let ctrl_c = ::tokio::signal::ctrl_c();
+    ::tokio::pin!(ctrl_c);
+    let (shutdown, shutdown_rx) = watch::channel(false);
+    let mut draining = Vec::new();
+
+    let mut serving: Option<Serving> = None;
+    let mut starting: Option<Starting> = None;
+    // Whether to start a new server, once `retry` says so.
+    let mut wanted = true;
+    let mut retry: Option<Instant> = None;
+    let mut backoff = BACKOFF_MIN;
+    let mut spawned = 0;
+    loop {
+        let mut failed = None;
+        if wanted && retry.is_none() {
+            wanted = false;
+            match build(&project_root, name.clone()) {
+                Ok((child, artifact)) => {
+                    status.rocket(match serving {
+                        Some(ref serving) => status::Rocket::Replacing {
+                            pid: serving.child.id(),
+                            port,
+                            next: None,
+                        },
+                        None => status::Rocket::Building,
+                    });
+                    starting = Some(Starting::Building { child, artifact });
+                }
+                Err(e) => failed = Some(format!("couldn't run cargo: {}", e)),
+            }
+        }
+
+        let event = match failed {
+            Some(_) => Event::Retry,
+            None => {
+                let (starting_child, starting_notify) = match starting {
+                    Some(Starting::Building { ref mut child, .. }) => (Some(child), None),
+                    Some(Starting::Running {
+                        ref mut child,
+                        ref mut notify,
+                    }) => (Some(child), Some(notify)),
+                    None => (None, None),
+                };
+                let retry_at = retry.unwrap_or_else(Instant::now);
+                ::tokio::select! {
+                    x = exited(serving.as_mut().map(|x| &mut x.child)) => Event::ServingExited(x),
+                    x = exited(starting_child) => Event::StartingExited(x),
+                    x = ready(starting_notify) => Event::Ready(x),
+                    action = srx.recv() => match action {
+                        Some(Some(ServerAction::Reload(_))) => Event::Reload,
+                        Some(None) => continue,
+                        None => Event::Shutdown,
+                    },
+                    _ = &mut ctrl_c => Event::Shutdown,
+                    _ = ::tokio::time::delay_until(retry_at.into()), if retry.is_some() => Event::Retry,
+                }
+            }
+        };
+
+        match event {
+            Event::Retry => retry = None,
+            Event::Ready(Err(e)) => {
+                // Without hearing it's ready, it'll never take over, so start again.
+                failed = Some(format!("couldn't hear from the new server: {}", e));
+                if let Some(mut x) = starting.take() {
+                    let _ = stop(x.child()).await;
+                }
+            }
+            Event::Ready(Ok(())) => {
+                if let Some(Starting::Running { child, notify }) = starting.take() {
+                    let pid = child.id();
+                    let old = serving.replace(Serving {
+                        child,
+                        notify,
+                        since: Instant::now(),
+                    });
+                    status.rocket(status::Rocket::Up { pid, port });
+                    log::info!("dev", "server is up on port {}", port);
+                    if let Some(mut old) = old {
+                        let old_pid = old.child.id();
+                        terminate(&mut old.child);
+                        let stopping = old.notify.wait("STOPPING=1");
+                        match ::tokio::time::timeout(STOPPING_TIMEOUT, stopping).await {
+                            Ok(Ok(())) => log::info!(
+                                "dev",
+                                "old server (pid {}) is finishing its requests",
+                                old_pid
+                            ),
+                            Ok(Err(e)) => log::warning!(
+                                "dev",
+                                "couldn't hear from the old server (pid {}): {}",
+                                old_pid,
+                                e
+                            ),
+                            Err(_) => log::warning!(
+                                "dev",
+                                "old server (pid {}) didn't say it's stopping within {}s",
+                                old_pid,
+                                STOPPING_TIMEOUT.as_secs()
+                            ),
+                        }
+                        draining.push(::tokio::spawn(drain(old.child, shutdown_rx.clone())));
+                    }
+                    tell(BrowserAction::BackendUp(RefreshToken::new()));
+                }
+            }
+            Event::Reload => {
+                log::info!("dev", "starting a new server");
+                status.rocket(status::Rocket::Restarting);
+                // Whatever's starting was built from older sources.
+                if let Some(mut x) = starting.take() {
+                    if let Err(e) = stop(x.child()).await {
+                        log::error!("dev", "couldn't stop the server: {}", e);
+                    }
+                }
+                if serving.is_none() {
+                    tell(BrowserAction::BackendDown("restarting the server".into()));
+                }
+                wanted = true;
+                retry = None;
+                backoff = BACKOFF_MIN;
+            }
+            Event::ServingExited(exit) => {
+                if let Some(mut x) = serving.take() {
+                    kill(&mut x.child);
+                    if x.since.elapsed() >= STABLE {
+                        backoff = BACKOFF_MIN;
+                    }
+                }
+                let why = match exit {
+                    Ok(x) => format!("the server stopped ({})", x),
+                    Err(e) => format!("couldn't wait for the server: {}", e),
+                };
+                if starting.is_some() {
+                    // Nothing to restart, since the new one's on its way.
+                    log::error!("dev", "{}", why);
+                    status.rocket(status::Rocket::Down(why.clone()));
+                    tell(BrowserAction::BackendDown(why));
+                } else {
+                    failed = Some(why);
+                }
+            }
+            Event::StartingExited(exit) => match starting.take() {
+                Some(Starting::Building {
+                    mut child,
+                    artifact,
+                }) => {
+                    kill(&mut child);
+                    let built = match exit {
+                        Ok(x) if x.success() => artifact
+                            .await
+                            .ok()
+                            .flatten()
+                            .ok_or_else(|| format!("cargo didn't say where it built {}", name)),
+                        Ok(x) => Err(format!("the server didn't build ({})", x)),
+                        Err(e) => Err(format!("couldn't wait for cargo: {}", e)),
+                    };
+                    // The new one binds the port itself, so the old one has to go first.
+                    #[cfg(not(unix))]
+                    if built.is_ok() {
+                        if let Some(mut old) = serving.take() {
+                            if let Err(e) = stop(&mut old.child).await {
+                                log::error!("dev", "couldn't stop the server: {}", e);
+                            }
+                        }
+                    }
+                    let started = built.and_then(|server| {
+                        spawned += 1;
+                        let notify = Notify::new(spawned, &socket)
+                            .map_err(|e| format!("couldn't make a notify socket: {}", e))?;
+                        spawn(&server, &project_root, &socket, &notify)
+                            .map(|child| (child, notify))
+                            .map_err(|e| format!("couldn't start {}: {}", server.display(), e))
+                    });
+                    match started {
+                        Ok((child, notify)) => {
+                            let next = child.id();
+                            status.rocket(match serving {
+                                Some(ref serving) => status::Rocket::Replacing {
+                                    pid: serving.child.id(),
+                                    port,
+                                    next: Some(next),
+                                },
+                                None => status::Rocket::Starting { pid: next },
+                            });
+                            starting = Some(Starting::Running { child, notify });
+                        }
+                        Err(why) => failed = Some(why),
+                    }
+                }
+                Some(Starting::Running { mut child, .. }) => {
+                    kill(&mut child);
+                    failed = Some(match exit {
+                        Ok(x) => format!("the server stopped ({})", x),
+                        Err(e) => format!("couldn't wait for the server: {}", e),
+                    });
+                }
+                None => (),
+            },
+            Event::Shutdown => break,
+        }
+
+        if let Some(why) = failed {
+            if let Some(ref x) = serving {
+                // It probably didn't build. Carry on with what works.
+                log::error!(
+                    "dev",
+                    "{}; the old server (pid {}) is still running",
+                    why,
+                    x.child.id()
+                );
+                status.rocket(status::Rocket::Up {
+                    pid: x.child.id(),
+                    port,
+                });
+                continue;
+            }
+            let message = format!(
+                "{}; restarting in {:.1}s, or when Rust sources change",
+                why,
+                backoff.as_secs_f64()
+            );
+            log::error!("dev", "{}", message);
+            status.rocket(status::Rocket::Down(why));
+            tell(BrowserAction::BackendDown(message));
+            wanted = true;
+            retry = Some(Instant::now() + backoff);
+            backoff = (backoff * 2).min(BACKOFF_MAX);
+        }
+    }
+
+    if let Some(mut x) = starting {
+        if let Err(e) = stop(x.child()).await {
+            log::error!("dev", "couldn't stop the server: {}", e);
+        }
+    }
+    if let Some(mut x) = serving {
+        if let Err(e) = stop(&mut x.child).await {
+            log::error!("dev", "couldn't stop the server: {}", e);
+        }
+    }
+    let _ = shutdown.broadcast(true);
+    for x in draining {
+        let _ = x.await;
+    }
+}
@@ -0,0 +1,344 @@
+//! Running every kind of test the project has, and adding up the results.
+//! That's `cargo test` for the Rust, `elm-test` for the Elm if it's installed,
+//! and the end to end suite in [`crate::e2e`].
+//! Each suite keeps going after a failure, so one run shows everything that's broken.
+use crate::runner::{self, StepError};
+use crate::OutputMethod;
+use ::std::fmt::Write as _;
+use ::std::fs;
+use ::std::path::Path;
+use ::std::process::Command;
+use ::std::time::{Duration, Instant};
+
+pub(crate) enum Outcome {
+    Passed,
+    Failed(String),
+    Skipped(String),
+}
+
+pub(crate) struct TestCase {
+    pub(crate) suite: &'static str,
+    pub(crate) name: String,
+    pub(crate) time: Duration,
+    pub(crate) outcome: Outcome,
+}
+
+/// Everything that ran, in order.
+#[derive(Default)]
+pub(crate) struct Report {
+    cases: Vec<TestCase>,
+}
+impl Report {
+    pub(crate) fn add(&mut self, case: TestCase) {
+        let status = match case.outcome {
+            Outcome::Passed => "ok".to_string(),
+            Outcome::Failed(_) => "FAILED".to_string(),
+            Outcome::Skipped(ref why) => format!("skipped ({})", why),
+        };
+        println!(
+            "[{}] {} ... {} ({:.2}s)",
+            case.suite,
+            case.name,
+            status,
+            case.time.as_secs_f64()
+        );
+        self.cases.push(case);
+    }
+
+    /// Time `f`, and add whatever it says happened.
+    pub(crate) fn run<F: FnOnce() -> Outcome>(&mut self, suite: &'static str, name: &str, f: F) {
+        let start = Instant::now();
+        let outcome = f();
+        self.add(TestCase {
+            suite,
+            name: name.into(),
+            time: start.elapsed(),
+            outcome,
+        });
+    }
+
+    pub(crate) fn failures(&self) -> usize {
+        self.cases
+            .iter()
+            .filter(|x| matches!(x.outcome, Outcome::Failed(_)))
+            .count()
+    }
+
+    pub(crate) fn summary(&self) -> String {
+        let skipped = self
+            .cases
+            .iter()
+            .filter(|x| matches!(x.outcome, Outcome::Skipped(_)))
+            .count();
+        let failed = self.failures();
+        format!(
+            "{} passed, {} failed, {} skipped",
+            self.cases.len() - failed - skipped,
+            failed,
+            skipped
+        )
+    }
+
+    /// Print the details of everything that failed.
+    pub(crate) fn print_failures(&self) {
+        for case in &self.cases {
+            if let Outcome::Failed(ref message) = case.outcome {
+                println!("-- [{}] {} --\n{}", case.suite, case.name, message);
+            }
+        }
+    }
+
+    /// The JUnit XML CI systems like, one `<testsuite>` per suite.
+    pub(crate) fn write_junit(&self, path: &Path) -> ::anyhow::Result<()> {
+        let mut suites: Vec<(&str, Vec<&TestCase>)> = Vec::new();
+        for case in &self.cases {
+            match suites.iter_mut().find(|(name, _)| *name == case.suite) {
+                Some((_, cases)) => cases.push(case),
+                None => suites.push((case.suite, vec![case])),
+            }
+        }
+        let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+        writeln!(
+            xml,
+            "<testsuites name=\"fileshare\" tests=\"{}\" failures=\"{}\">",
+            self.cases.len(),
+            self.failures()
+        )?;
+        for (suite, cases) in suites {
+            let count = |f: fn(&Outcome) -> bool| cases.iter().filter(|x| f(&x.outcome)).count();
+            writeln!(
+                xml,
+                "  <testsuite name=\"{}\" tests=\"{}\" failures=\"{}\" skipped=\"{}\" time=\"{:.3}\">",
+                escape(suite),
+                cases.len(),
+                count(|x| matches!(x, Outcome::Failed(_))),
+                count(|x| matches!(x, Outcome::Skipped(_))),
+                cases.iter().map(|x| x.time).sum::<Duration>().as_secs_f64()
+            )?;
+            for case in cases {
+                write!(
+                    xml,
+                    "    <testcase classname=\"{}\" name=\"{}\" time=\"{:.3}\"",
+                    escape(suite),
+                    escape(&case.name),
+                    case.time.as_secs_f64()
+                )?;
+                match case.outcome {
+                    Outcome::Passed => xml.push_str("/>\n"),
+                    Outcome::Failed(ref message) => writeln!(
+                        xml,
+                        ">\n      <failure message=\"{}\">{}</failure>\n    </testcase>",
+                        escape(message.lines().next().unwrap_or("")),
+                        escape(message)
+                    )?,
+                    Outcome::Skipped(ref why) => writeln!(
+                        xml,
+                        ">\n      <skipped message=\"{}\"/>\n    </testcase>",
+                        escape(why)
+                    )?,
+                }
+            }
+            xml.push_str("  </testsuite>\n");
+        }
+        xml.push_str("</testsuites>\n");
+        ::fsio::file::ensure_exists(&path.to_path_buf()).map_err(|e| ::anyhow::anyhow!(e))?;
+        fs::write(path, xml)?;
+        Ok(())
+    }
+}
+
+fn escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&apos;"),
+            // Control characters aren't allowed in XML at all.
+            c if c.is_control() && c != '\n' && c != '\t' && c != '\r' => (),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Turn a step's result into a test outcome.
+fn outcome(result: Result<::std::process::Output, StepError>) -> Outcome {
+    match result {
+        Ok(_) => Outcome::Passed,
+        Err(e) => Outcome::Failed(failure(&e)),
+    }
+}
+
+/// What went wrong, with stderr, even if it's been on the console already,
+/// since the report's read on its own.
+fn failure(e: &StepError) -> String {
+    match e {
+        StepError::Failed {
+            output,
+            forwarded: true,
+            ..
+        } => format!(
+            "{}\n{}",
+            e,
+            String::from_utf8_lossy(&output.stderr).trim_end()
+        ),
+        _ => e.to_string(),
+    }
+}
+
+/// `cargo test` over the whole workspace, with a case for each test.
+/// If it fails without any test failing, it didn't build, and that's the case.
+pub(crate) fn cargo(report: &mut Report, project_root: &Path) {
+    let start = Instant::now();
+    let mut c = Command::new("cargo");
+    c.arg("test")
+        .arg("--workspace")
+        .arg("--manifest-path")
+        .arg(project_root.join("Cargo.toml"));
+    let result = runner::run("cargo test", &mut c, OutputMethod::Forward);
+    let stdout = match result {
+        Ok(ref output) | Err(StepError::Failed { ref output, .. }) => {
+            String::from_utf8_lossy(&output.stdout).into_owned()
+        }
+        Err(_) => String::new(),
+    };
+    let mut any_failed = false;
+    for (name, outcome) in libtest_results(&stdout) {
+        any_failed |= matches!(outcome, Outcome::Failed(_));
+        report.add(TestCase {
+            suite: "cargo",
+            name,
+            time: Duration::default(),
+            outcome,
+        });
+    }
+    if let Err(e) = result {
+        if !any_failed {
+            report.add(TestCase {
+                suite: "cargo",
+                name: "cargo test --workspace".into(),
+                time: start.elapsed(),
+                outcome: Outcome::Failed(failure(&e)),
+            });
+        }
+    }
+}
+
+/// Each test's result, from the usual libtest output,
+/// since its JSON output is nightly only.
+/// Failures come with whatever the test printed.
+fn libtest_results(stdout: &str) -> Vec<(String, Outcome)> {
+    let mut results = Vec::new();
+    let mut printed: Vec<(String, String)> = Vec::new();
+    let mut printing = false;
+    for line in stdout.lines() {
+        let header = line
+            .strip_prefix("---- ")
+            .and_then(|x| x.strip_suffix(" stdout ----"));
+        if let Some(name) = header {
+            printed.push((name.into(), String::new()));
+            printing = true;
+        } else if line == "failures:" || line.starts_with("test result:") {
+            printing = false;
+        } else if printing {
+            if let Some((_, text)) = printed.last_mut() {
+                text.push_str(line);
+                text.push('\n');
+            }
+        } else if let Some(rest) = line.strip_prefix("test ") {
+            let (name, status) = match rest.rfind(" ... ") {
+                Some(i) => (&rest[..i], &rest[i + 5..]),
+                None => continue,
+            };
+            let outcome = match status {
+                "ok" => Outcome::Passed,
+                "FAILED" => Outcome::Failed(String::new()),
+                "ignored" => Outcome::Skipped("ignored".into()),
+                x => match x.strip_prefix("ignored, ") {
+                    Some(why) => Outcome::Skipped(why.into()),
+                    None => continue,
+                },
+            };
+            results.push((name.to_string(), outcome));
+        }
+    }
+    for (name, outcome) in &mut results {
+        if let Outcome::Failed(ref mut message) = outcome {
+            *message = match printed.iter().find(|(x, _)| x == name) {
+                Some((_, text)) if !text.trim().is_empty() => text.trim_end().into(),
+                _ => "failed".into(),
+            };
+        }
+    }
+    results
+}
+
+/// `elm-test`, if it's installed and there's anything for it to do.
+pub(crate) fn elm(report: &mut Report, elm_project_dir: &Path) {
+    report.run("elm", "elm-test", || {
+        let elm_test = match ::which::which("elm-test") {
+            Ok(x) => x,
+            Err(_) => return Outcome::Skipped("elm-test isn't installed".into()),
+        };
+        if !elm_project_dir.join("tests").is_dir() {
+            return Outcome::Skipped("there's no tests directory".into());
+        }
+        let mut c = Command::new(elm_test);
+        c.current_dir(elm_project_dir);
+        outcome(runner::run("elm-test", &mut c, OutputMethod::Forward))
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn libtest_output_is_split_into_cases() {
+        let stdout = "
+running 4 tests
+test a::works ... ok
+test a::breaks ... FAILED
+test b::later ... ignored
+test b::slow ... ignored, takes a minute
+
+failures:
+
+---- a::breaks stdout ----
+thread 'a::breaks' panicked at 'assertion failed: false', src/a.rs:3:5
+
+failures:
+    a::breaks
+
+test result: FAILED. 1 passed; 1 failed; 2 ignored; 0 measured; 0 filtered out
+
+running 1 test
+test src/lib.rs - f (line 3) ... ok
+
+test result: ok. 1 passed; 0 failed; 0 ignored; 0 measured; 0 filtered out
+";
+        let results = libtest_results(stdout);
+        let names: Vec<_> = results.iter().map(|(x, _)| x.as_str()).collect();
+        assert_eq!(
+            names,
+            [
+                "a::works",
+                "a::breaks",
+                "b::later",
+                "b::slow",
+                "src/lib.rs - f (line 3)"
+            ]
+        );
+        assert!(matches!(results[0].1, Outcome::Passed));
+        match results[1].1 {
+            Outcome::Failed(ref x) => assert!(x.starts_with("thread 'a::breaks' panicked")),
+            _ => panic!("a::breaks should've failed"),
+        }
+        assert!(matches!(results[2].1, Outcome::Skipped(ref x) if x == "ignored"));
+        assert!(matches!(results[3].1, Outcome::Skipped(ref x) if x == "takes a minute"));
+        assert!(matches!(results[4].1, Outcome::Passed));
+    }
+}
@@ -0,0 +1,172 @@
+//! Getting hold of `elm` and `terser` when they aren't installed,
+//! or when we want exactly the versions the project pins.
+//! Pinned tools get downloaded once into a per-user cache,
+//! from a local directory of artifacts if there is one,
+//! or from the configured mirror otherwise.
+//! Either way, nothing gets used unless its SHA-256 matches the pin.
+use crate::config::{ToolPin, ToolchainConfig};
+use ::std::fs;
+use ::std::io::{self, Read};
+use ::std::path::{Path, PathBuf};
+
+#[derive(Debug, ::thiserror::Error)]
+pub(crate) enum ToolchainError {
+    #[error("{tool} {version} has no artifact pinned for {platform}")]
+    NoArtifact {
+        tool: String,
+        version: String,
+        platform: String,
+    },
+    #[error(
+        "couldn't find {file} in the local toolchain directory, and there's no mirror configured"
+    )]
+    NoSource { file: String },
+    #[error("checksum mismatch for {file}: expected {expected}, got {actual}")]
+    Checksum {
+        file: String,
+        expected: String,
+        actual: String,
+    },
+    #[error("downloading {url}: {message}")]
+    Download { url: String, message: String },
+    #[error("couldn't find a cache directory for the toolchain; set `toolchain.cache_dir`")]
+    NoCacheDir,
+}
+
+/// Like `linux-x86_64`, which is how pins name their platforms.
+pub(crate) fn platform() -> String {
+    format!("{}-{}", ::std::env::consts::OS, ::std::env::consts::ARCH)
+}
+
+pub(crate) struct Toolchain<'a> {
+    config: &'a ToolchainConfig,
+    cache_dir: PathBuf,
+}
+impl<'a> Toolchain<'a> {
+    pub(crate) fn new(config: &'a ToolchainConfig) -> Result<Self, ToolchainError> {
+        let cache_dir = match config.cache_dir {
+            Some(ref x) => x.clone(),
+            None => ::dirs::cache_dir()
+                .ok_or(ToolchainError::NoCacheDir)?
+                .join("fileshare-build")
+                .join("toolchain"),
+        };
+        Ok(Self { config, cache_dir })
+    }
+
+    pub(crate) fn cache_dir(&self) -> &Path {
+        &self.cache_dir
+    }
+
+    /// Make sure the pinned version of `tool` is installed,
+    /// and return the path to its executable.
+    pub(crate) fn ensure(&self, tool: &str, pin: &ToolPin) -> ::anyhow::Result<PathBuf> {
+        let platform = platform();
+        let artifact = pin
+            .platforms
+            .get(&platform)
+            .ok_or_else(|| ToolchainError::NoArtifact {
+                tool: tool.into(),
+                version: pin.version.clone(),
+                platform: platform.clone(),
+            })?;
+        // The hash is in the name, so changing the pin can't reuse a stale install.
+        let install_dir = self.cache_dir.join(format!(
+            "{}-{}-{}",
+            tool,
+            pin.version,
+            &artifact.sha256[..artifact.sha256.len().min(16)]
+        ));
+        // What a lone executable gets called, `elm.exe` on Windows.
+        let exe = format!("{}{}", tool, ::std::env::consts::EXE_SUFFIX);
+        let bin = match artifact.bin {
+            Some(ref x) => install_dir.join(x),
+            None => install_dir.join(&exe),
+        };
+        if bin.is_file() {
+            return Ok(bin);
+        }
+
+        println!("installing {} {} for {}", tool, pin.version, platform);
+        let data = self.fetch(&artifact.file)?;
+        let actual = ::hex::encode(<::sha2::Sha256 as ::sha2::Digest>::digest(&data));
+        if !actual.eq_ignore_ascii_case(&artifact.sha256) {
+            return Err(ToolchainError::Checksum {
+                file: artifact.file.clone(),
+                expected: artifact.sha256.clone(),
+                actual,
+            }
+            .into());
+        }
+
+        // Unpack somewhere else first, so a half finished install
+        // never looks like a finished one.
+        fs::create_dir_all(&self.cache_dir)?;
+        let staging = self
+            .cache_dir
+            .join(format!(".{}-{}.partial", tool, ::std::process::id()));
+        let _ = fs::remove_dir_all(&staging);
+        fs::create_dir_all(&staging)?;
+        unpack(&artifact.file, &data, &staging, &exe)?;
+        let _ = fs::remove_dir_all(&install_dir);
+        fs::rename(&staging, &install_dir)?;
+        make_executable(&bin)?;
+        Ok(bin)
+    }
+
+    /// Get an artifact's bytes, preferring the local directory.
+    fn fetch(&self, file: &str) -> ::anyhow::Result<Vec<u8>> {
+        if let Some(ref dir) = self.config.local_dir {
+            let path = dir.join(file);
+            if path.is_file() {
+                return Ok(fs::read(path)?);
+            }
+        }
+        let mirror = match self.config.mirror {
+            Some(ref x) => x,
+            None => return Err(ToolchainError::NoSource { file: file.into() }.into()),
+        };
+        let url = format!("{}/{}", mirror.trim_end_matches('/'), file);
+        let download_error = |message: String| ToolchainError::Download {
+            url: url.clone(),
+            message,
+        };
+        let response = ::ureq::get(&url)
+            .call()
+            .map_err(|e| download_error(e.to_string()))?;
+        let mut data = Vec::new();
+        response
+            .into_reader()
+            .read_to_end(&mut data)
+            .map_err(|e| download_error(e.to_string()))?;
+        Ok(data)
+    }
+}
+
+/// Artifacts are a `.tar.gz` or `.tgz` of a whole install,
+/// a lone gzipped executable, like Elm's releases,
+/// or a plain executable.
+fn unpack(file: &str, data: &[u8], dest: &Path, exe: &str) -> io::Result<()> {
+    if file.ends_with(".tar.gz") || file.ends_with(".tgz") {
+        ::tar::Archive::new(::flate2::read::GzDecoder::new(data)).unpack(dest)
+    } else if file.ends_with(".gz") {
+        let mut bin = Vec::new();
+        ::flate2::read::GzDecoder::new(data).read_to_end(&mut bin)?;
+        fs::write(dest.join(exe), bin)
+    } else {
+        fs::write(dest.join(exe), data)
+    }
+}
+
+#[cfg(unix)]
+fn make_executable(path: &Path) -> io::Result<()> {
+    use ::std::os::unix::fs::PermissionsExt;
+    let mut permissions = fs::metadata(path)?.permissions();
+    permissions.set_mode(permissions.mode() | 0o755);
+    fs::set_permissions(path, permissions)
+}
+
+#[cfg(not(unix))]
+fn make_executable(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
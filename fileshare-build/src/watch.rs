@@ -0,0 +1,262 @@
+//! Watching the source directory, and rebuilding what changed.
+//! The dev server uses this to know when to reload the page,
+//! and `build --watch` uses it on its own, to just keep `static/` up to date.
+use crate::config::Config;
+use crate::log;
+use crate::manifest::Manifest;
+use crate::protocol::write_reload_config;
+use crate::rules::{Kind, Rules};
+use crate::Binaries;
+use crate::{copy, elm, ElmFailure, OutputMethod};
+use ::notify::{DebouncedEvent, RecommendedWatcher, RecursiveMode, Watcher};
+use ::std::path::Path;
+use ::std::sync::mpsc::Receiver;
+use ::std::sync::Arc;
+use ::std::time::{Duration, Instant};
+
+fn event_path(event: &DebouncedEvent) -> Option<&Path> {
+    match event {
+        DebouncedEvent::NoticeRemove(x)
+        | DebouncedEvent::Create(x)
+        | DebouncedEvent::Write(x)
+        | DebouncedEvent::Chmod(x)
+        | DebouncedEvent::Remove(x)
+        | DebouncedEvent::Rename(x, _) => Some(x),
+        _ => None,
+    }
+}
+
+/// Start watching the source directory.
+/// Events stop coming once the watcher is dropped.
+pub(crate) fn watch(
+    config: &Config,
+) -> ::anyhow::Result<(RecommendedWatcher, Receiver<DebouncedEvent>)> {
+    let (wtx, wrx) = ::std::sync::mpsc::channel();
+    let mut watcher: RecommendedWatcher = Watcher::new(wtx, Duration::from_secs(0))?;
+    watcher.watch(&config.build.source_dir, RecursiveMode::Recursive)?;
+    Ok((watcher, wrx))
+}
+
+/// What kinds of things some events touched.
+#[derive(Default, Debug, Clone, Copy)]
+pub(crate) struct Changes {
+    pub(crate) copies: bool,
+    pub(crate) elm: bool,
+    pub(crate) rust: bool,
+}
+impl Changes {
+    /// Note whatever `event` touched.
+    pub(crate) fn add(&mut self, rules: &Rules, event: &DebouncedEvent) {
+        let path = match event_path(event) {
+            Some(x) => x,
+            None => return,
+        };
+        let mut mark = |kind| match kind {
+            Kind::Copy => self.copies = true,
+            Kind::Elm => self.elm = true,
+            Kind::Rust => self.rust = true,
+        };
+        if path.is_dir() {
+            for (_, kind) in rules.walk(path) {
+                mark(kind);
+            }
+        } else if let Some(kind) = rules.classify(path) {
+            mark(kind);
+        }
+    }
+
+    /// Whether the site needs rebuilding.
+    pub(crate) fn site(&self) -> bool {
+        self.copies || self.elm
+    }
+}
+
+/// How the site looks after a rebuild.
+pub(crate) struct Rebuilt {
+    pub(crate) time: Duration,
+    /// What's wrong with it, if anything.
+    /// This sticks around until the Elm builds again,
+    /// even if only copies changed since.
+    pub(crate) error: Option<String>,
+}
+
+/// Everything it takes to rebuild the site, over and over.
+pub(crate) struct Rebuilder {
+    config: Config,
+    rules: Arc<Rules>,
+    bins: Binaries,
+    manifest: Manifest,
+    error: Option<String>,
+}
+impl Rebuilder {
+    pub(crate) fn new(
+        config: Config,
+        rules: Arc<Rules>,
+        bins: Binaries,
+        mut manifest: Manifest,
+    ) -> Self {
+        manifest.set_tools(bins.versions.clone());
+        Self {
+            config,
+            rules,
+            bins,
+            manifest,
+            error: None,
+        }
+    }
+
+    /// Build everything that's out of date.
+    pub(crate) fn build(&mut self) -> ::anyhow::Result<Vec<ElmFailure>> {
+        copy(&self.config, &self.rules, &mut self.manifest)?;
+        let failures = elm(
+            &self.config,
+            &self.rules,
+            &mut self.manifest,
+            &self.bins.elm,
+            self.bins.terser.as_deref(),
+            false,
+            OutputMethod::Capture,
+        );
+        self.manifest.save()?;
+        failures
+    }
+
+    /// Rebuild whatever `changes` touched.
+    pub(crate) fn rebuild(&mut self, changes: Changes) -> Rebuilt {
+        let start = Instant::now();
+        if changes.copies {
+            if let Err(e) = copy(&self.config, &self.rules, &mut self.manifest) {
+                log::error!("copy", "couldn't copy assets: {:#}", e);
+            }
+        }
+        if changes.elm {
+            let result = elm(
+                &self.config,
+                &self.rules,
+                &mut self.manifest,
+                &self.bins.elm,
+                self.bins.terser.as_deref(),
+                false,
+                OutputMethod::Capture,
+            );
+            self.error = match result {
+                Ok(failures) if failures.is_empty() => None,
+                Ok(failures) => Some(
+                    failures
+                        .iter()
+                        .map(ElmFailure::report)
+                        .collect::<Vec<_>>()
+                        .join("\n"),
+                ),
+                // Terser, probably.
+                Err(e) => Some(format!("{:#}", e)),
+            };
+        }
+        if let Err(e) = self.manifest.save() {
+            log::error!("build", "couldn't save build manifest: {}", e);
+        }
+        Rebuilt {
+            time: start.elapsed(),
+            error: self.error.clone(),
+        }
+    }
+}
+
+/// Say how a rebuild went, briefly unless it went wrong.
+pub(crate) fn report(rebuilt: &Rebuilt) {
+    match rebuilt.error {
+        None => log::info!("build", "rebuilt in {:.2}s", rebuilt.time.as_secs_f64()),
+        Some(ref error) => {
+            log::error!("elm", "{}", error);
+            log::info!(
+                "build",
+                "rebuild failed after {:.2}s",
+                rebuilt.time.as_secs_f64()
+            );
+        }
+    }
+}
+
+/// `build --watch`: keep the site up to date, and nothing else.
+/// No live reload server, no TLS and no Rocket,
+/// so this works alongside however the server's being run.
+#[::tokio::main]
+pub(crate) async fn build(
+    config: Config,
+    bins: Binaries,
+    rules: Rules,
+    manifest: Manifest,
+) -> ::anyhow::Result<()> {
+    let rules = Arc::new(rules);
+    let source_dir = config.build.source_dir.clone();
+    let output_dir = config.build.output_dir.clone();
+    let (_watcher, wrx) = watch(&config)?;
+    let mut rebuilder = Rebuilder::new(config, rules.clone(), bins, manifest);
+
+    let start = Instant::now();
+    let failures = rebuilder.build()?;
+    // There's no dev server for `reload.js` to talk to.
+    write_reload_config(&output_dir, None)?;
+    if failures.is_empty() {
+        log::info!("build", "built in {:.2}s", start.elapsed().as_secs_f64());
+    } else {
+        for failure in &failures {
+            log::error!("elm", "{}", failure.report());
+        }
+        log::info!(
+            "build",
+            "build failed, {} Elm programs broken",
+            failures.len()
+        );
+    }
+    log::info!(
+        "watch",
+        "watching {} (Ctrl-C to stop)",
+        source_dir.display()
+    );
+
+    // `notify` wants a std channel, and Ctrl-C is a future,
+    // so the events get moved over to a tokio channel to wait on both.
+    let (tx, mut rx) = ::tokio::sync::mpsc::unbounded_channel();
+    ::std::thread::spawn(move || {
+        for event in wrx {
+            if tx.send(event).is_err() {
+                break;
+            }
+        }
+    });
+    let ctrl_c = ::tokio::signal::ctrl_c();
+    ::tokio::pin!(ctrl_c);
+    loop {
+        let event = ::tokio::select! {
+            x = &mut ctrl_c => {
+                x?;
+                break;
+            }
+            x = rx.recv() => match x {
+                Some(x) => x,
+                None => ::anyhow::bail!("stopped getting file events"),
+            },
+        };
+        // One save tends to be a few events, so take everything that's waiting.
+        let mut changes = Changes::default();
+        log::debug!("watch", "{:?}", event);
+        changes.add(&rules, &event);
+        while let Ok(event) = rx.try_recv() {
+            log::debug!("watch", "{:?}", event);
+            changes.add(&rules, &event);
+        }
+        if changes.rust {
+            log::info!(
+                "watch",
+                "Rust sources changed; the server needs rebuilding separately"
+            );
+        }
+        if !changes.site() {
+            continue;
+        }
+        report(&rebuilder.rebuild(changes));
+    }
+    log::info!("watch", "stopped watching");
+    Ok(())
+}
@@ -0,0 +1,45 @@
+-- The admin console.
+-- Kept apart from the uploader so that regular users never download it.
+module Admin exposing (..)
+
+import Browser
+import Browser.Navigation as Nav
+import Html exposing (Html)
+import Url
+
+-- Main
+main : Program () Model Msg
+main = Browser.application
+       { init = init
+       , view = view
+       , update = update
+       , subscriptions = subscriptions
+       , onUrlChange = UrlChanged
+       , onUrlRequest = LinkClicked
+       }
+
+-- Model
+type alias Model = {}
+
+init : () -> Url.Url -> Nav.Key -> (Model, Cmd Msg)
+init flags url key = (Model, Cmd.none)
+
+-- Update
+type Msg
+    = LinkClicked Browser.UrlRequest
+    | UrlChanged Url.Url
+
+update : Msg -> Model -> (Model, Cmd Msg)
+update msg model = (model, Cmd.none)
+
+-- Subscriptions
+subscriptions : Model -> Sub Msg
+subscriptions _ = Sub.none
+
+-- View
+view : Model -> Browser.Document Msg
+view model =
+    { title = "Fileshare Admin"
+    , body =
+        [ Html.text "I am the admin console!" ]
+    }
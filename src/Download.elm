@@ -0,0 +1,40 @@
+-- The page someone lands on when they've been sent a link.
+-- This is deliberately tiny, since the recipient just wants their file,
+-- and shouldn't have to download the whole uploader to get it.
+module Download exposing (..)
+
+import Browser
+import Html exposing (Html)
+import Html.Attributes as Attr
+
+-- Main
+main : Program () Model Msg
+main = Browser.element
+       { init = init
+       , view = view
+       , update = update
+       , subscriptions = subscriptions
+       }
+
+-- Model
+type alias Model = {}
+
+init : () -> (Model, Cmd Msg)
+init flags = (Model, Cmd.none)
+
+-- Update
+type Msg
+    = NoOp
+
+update : Msg -> Model -> (Model, Cmd Msg)
+update msg model = (model, Cmd.none)
+
+-- Subscriptions
+subscriptions : Model -> Sub Msg
+subscriptions _ = Sub.none
+
+-- View
+view : Model -> Html Msg
+view model =
+    Html.div [ Attr.class "download" ]
+        [ Html.text "Someone shared a file with you!" ]
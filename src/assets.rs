@@ -0,0 +1,422 @@
+//! Serving the built site.
+//! This does what `StaticFiles` did, plus cache headers.
+//! Fingerprinted assets never change, so browsers can keep them forever.
+//! HTML has to be checked every time, so that it picks up new fingerprints.
+//! On release builds, text assets come with `.gz` and `.br` copies,
+//! and we serve whichever of those the browser likes best.
+//! Source maps are only served by debug builds of the server,
+//! so production doesn't hand out a map of the client code.
+//! That's the compile profile, not the Rocket environment:
+//! a debug build run with `ROCKET_ENV=production` still serves them.
+//!
+//! With the `embed-assets` feature, the whole site is baked into the binary
+//! by `fileshare-build build --embed`, and served from memory, ETags and all.
+use ::rocket::http::{ContentType, Header, Status};
+use ::rocket::request::{self, FromRequest, Request};
+use ::rocket::response::{self, NamedFile, Responder, Response};
+use ::rocket::{get, State};
+use ::std::collections::{HashMap, HashSet};
+use ::std::path::{Path, PathBuf};
+
+/// Written by `fileshare-build` next to the assets on release builds.
+/// Maps plain names to fingerprinted ones.
+const ASSET_MANIFEST: &str = "asset-manifest.json";
+
+/// Precompressed variants `fileshare-build` writes,
+/// by `Content-Encoding` and file extension, best first.
+const ENCODINGS: &[(&str, &str)] = &[("br", "br"), ("gzip", "gz")];
+
+const IMMUTABLE: &str = "public, max-age=31536000, immutable";
+const NO_CACHE: &str = "no-cache";
+
+/// Generated by `fileshare-build`, which puts the entries in `ASSETS`.
+#[cfg(feature = "embed-assets")]
+mod embedded {
+    pub struct EmbeddedAsset {
+        /// Relative to the site root, with forward slashes.
+        pub name: &'static str,
+        /// Hash of the uncompressed contents.
+        pub etag: &'static str,
+        pub fingerprinted: bool,
+        pub identity: &'static [u8],
+        pub gzip: Option<&'static [u8]>,
+        pub brotli: Option<&'static [u8]>,
+    }
+    include!(env!("FILESHARE_EMBEDDED_ASSETS"));
+}
+
+enum Source {
+    Dir {
+        root: PathBuf,
+        /// Names relative to `root`, with forward slashes.
+        fingerprinted: HashSet<String>,
+    },
+    #[cfg(feature = "embed-assets")]
+    Embedded(HashMap<&'static str, &'static embedded::EmbeddedAsset>),
+}
+
+pub struct Assets {
+    source: Source,
+    /// Set for debug builds.
+    source_maps: bool,
+}
+impl Assets {
+    /// Serve the site from a directory.
+    pub fn new<P: Into<PathBuf>>(root: P) -> Self {
+        let root = root.into();
+        // No manifest just means this isn't a release build,
+        // and nothing gets the special treatment.
+        let fingerprinted = ::std::fs::read(root.join(ASSET_MANIFEST))
+            .ok()
+            .and_then(|x| ::serde_json::from_slice::<HashMap<String, String>>(&x).ok())
+            .map(|x| x.into_iter().map(|(_, v)| v).collect())
+            .unwrap_or_default();
+        Self {
+            source: Source::Dir {
+                root,
+                fingerprinted,
+            },
+            source_maps: cfg!(debug_assertions),
+        }
+    }
+
+    /// Serve the site baked into the binary.
+    #[cfg(feature = "embed-assets")]
+    pub fn embedded() -> Self {
+        Self {
+            source: Source::Embedded(embedded::ASSETS.iter().map(|x| (x.name, x)).collect()),
+            source_maps: cfg!(debug_assertions),
+        }
+    }
+
+    fn cache_control(fingerprinted: bool, name: &str) -> Option<&'static str> {
+        if fingerprinted {
+            Some(IMMUTABLE)
+        } else if name.ends_with(".html") {
+            Some(NO_CACHE)
+        } else {
+            None
+        }
+    }
+
+    /// Find the file for a request path. Directories mean their `index.html`.
+    async fn open(&self, path: &Path, accept: &AcceptEncoding) -> Option<Asset> {
+        if !self.source_maps && is_source_map(path) {
+            return None;
+        }
+        let asset = match self.source {
+            Source::Dir {
+                ref root,
+                ref fingerprinted,
+            } => Self::open_file(root, fingerprinted, path, accept).await?,
+            #[cfg(feature = "embed-assets")]
+            Source::Embedded(ref assets) => Self::open_embedded(assets, path, accept)?,
+        };
+        Some(asset)
+    }
+
+    async fn open_file(
+        root: &Path,
+        fingerprinted: &HashSet<String>,
+        path: &Path,
+        accept: &AcceptEncoding,
+    ) -> Option<Asset> {
+        let mut full = root.join(path);
+        let mut path = path.to_path_buf();
+        if full.is_dir() {
+            full.push("index.html");
+            path.push("index.html");
+        }
+        let name = logical_name(&path);
+        // The original has to be there too,
+        // or we'd serve stale compressed copies of deleted files.
+        if !full.is_file() {
+            return None;
+        }
+        let available = compressed_variants(&full);
+        let varies = !available.is_empty();
+        let (encoding, file) = match accept.choose(available) {
+            Some((encoding, variant)) => (Some(encoding), NamedFile::open(variant).await.ok()?),
+            None => (None, NamedFile::open(&full).await.ok()?),
+        };
+        Some(Asset {
+            body: Body::File(file),
+            content_type: content_type(&name),
+            encoding,
+            varies,
+            cache_control: Self::cache_control(fingerprinted.contains(&name), &name),
+            etag: None,
+            name,
+        })
+    }
+
+    #[cfg(feature = "embed-assets")]
+    fn open_embedded(
+        assets: &HashMap<&'static str, &'static embedded::EmbeddedAsset>,
+        path: &Path,
+        accept: &AcceptEncoding,
+    ) -> Option<Asset> {
+        let name = logical_name(path);
+        let index = if name.is_empty() {
+            "index.html".to_string()
+        } else {
+            format!("{}/index.html", name)
+        };
+        let asset = assets
+            .get(name.as_str())
+            .or_else(|| assets.get(index.as_str()))?;
+        let available = ENCODINGS
+            .iter()
+            .filter_map(|&(encoding, _)| {
+                let body = match encoding {
+                    "br" => asset.brotli,
+                    "gzip" => asset.gzip,
+                    _ => None,
+                };
+                body.map(|x| (encoding, x))
+            })
+            .collect::<Vec<_>>();
+        let varies = !available.is_empty();
+        let (encoding, body) = match accept.choose(available) {
+            Some((encoding, body)) => (Some(encoding), body),
+            None => (None, asset.identity),
+        };
+        // Each encoding is its own representation, as far as caches care.
+        let etag = match encoding {
+            Some(x) => format!("\"{}-{}\"", asset.etag, x),
+            None => format!("\"{}\"", asset.etag),
+        };
+        Some(Asset {
+            body: Body::Bytes(body),
+            content_type: content_type(asset.name),
+            encoding,
+            varies,
+            cache_control: Self::cache_control(asset.fingerprinted, asset.name),
+            etag: Some(etag),
+            name: asset.name.to_string(),
+        })
+    }
+}
+
+/// The compressed copies of `full` that are worth serving, as `(coding, path)`.
+/// A copy older than the original is left over from some earlier build,
+/// like a release build followed by a debug one, so it's ignored.
+fn compressed_variants(full: &Path) -> Vec<(&'static str, PathBuf)> {
+    let modified = |path: &Path| ::std::fs::metadata(path).and_then(|x| x.modified()).ok();
+    let original = match modified(full) {
+        Some(x) => x,
+        None => return Vec::new(),
+    };
+    let mut available = Vec::new();
+    for &(encoding, extension) in ENCODINGS {
+        let mut variant = full.as_os_str().to_owned();
+        variant.push(".");
+        variant.push(extension);
+        let variant = PathBuf::from(variant);
+        if modified(&variant).map_or(false, |x| x >= original) {
+            available.push((encoding, variant));
+        }
+    }
+    available
+}
+
+/// Whether a request is for a source map, or a compressed copy of one.
+fn is_source_map(path: &Path) -> bool {
+    let name = logical_name(path);
+    let name = ENCODINGS
+        .iter()
+        .find_map(|&(_, extension)| name.strip_suffix(&format!(".{}", extension)))
+        .unwrap_or(&name);
+    name.ends_with(".map")
+}
+
+/// `a/b/c.js`, with forward slashes no matter the platform.
+fn logical_name(path: &Path) -> String {
+    path.components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Going by the original name, not whatever compressed copy we picked.
+fn content_type(name: &str) -> Option<ContentType> {
+    Path::new(name)
+        .extension()
+        .and_then(|x| ContentType::from_extension(&x.to_string_lossy()))
+}
+
+/// The browser's `Accept-Encoding`, as `(coding, q)` pairs.
+pub struct AcceptEncoding(Vec<(String, f32)>);
+impl AcceptEncoding {
+    fn parse<'a, I: Iterator<Item = &'a str>>(headers: I) -> Self {
+        let mut codings = Vec::new();
+        for item in headers.flat_map(|x| x.split(',')) {
+            let mut parts = item.split(';').map(str::trim);
+            let coding = match parts.next() {
+                Some(x) if !x.is_empty() => x.to_ascii_lowercase(),
+                _ => continue,
+            };
+            let q = parts
+                .filter_map(|p| p.strip_prefix("q="))
+                .filter_map(|q| q.parse().ok())
+                .next()
+                .unwrap_or(1.0);
+            codings.push((coding, q));
+        }
+        Self(codings)
+    }
+
+    /// The variant the browser likes best, out of `(coding, variant)` pairs,
+    /// if it wants any of them at all.
+    fn choose<T>(&self, available: Vec<(&'static str, T)>) -> Option<(&'static str, T)> {
+        let mut chosen: Option<(&'static str, T, f32)> = None;
+        for (coding, variant) in available {
+            let q = self.quality(coding);
+            if q > 0.0 && chosen.as_ref().map_or(true, |&(_, _, best)| q > best) {
+                chosen = Some((coding, variant, q));
+            }
+        }
+        chosen.map(|(coding, variant, _)| (coding, variant))
+    }
+
+    /// How much the browser wants `coding`. Zero means not at all.
+    fn quality(&self, coding: &str) -> f32 {
+        let exact = self.0.iter().find(|(c, _)| c == coding);
+        let wildcard = self.0.iter().find(|(c, _)| c == "*");
+        match exact.or(wildcard) {
+            Some(&(_, q)) => q,
+            None => 0.0,
+        }
+    }
+}
+#[::rocket::async_trait]
+impl<'a, 'r> FromRequest<'a, 'r> for AcceptEncoding {
+    type Error = ::std::convert::Infallible;
+    async fn from_request(req: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        request::Outcome::Success(Self::parse(req.headers().get("Accept-Encoding")))
+    }
+}
+
+enum Body {
+    File(NamedFile),
+    Bytes(&'static [u8]),
+}
+
+pub struct Asset {
+    body: Body,
+    /// Relative to the site root, with forward slashes.
+    name: String,
+    content_type: Option<ContentType>,
+    encoding: Option<&'static str>,
+    /// Whether there's more than one version of this to choose from.
+    varies: bool,
+    cache_control: Option<&'static str>,
+    /// Only embedded assets have one, since they're hashed ahead of time.
+    etag: Option<String>,
+}
+impl Asset {
+    /// Whether the browser already has this exact version.
+    fn not_modified(&self, req: &Request<'_>) -> bool {
+        let etag = match self.etag {
+            Some(ref x) => x,
+            None => return false,
+        };
+        req.headers()
+            .get("If-None-Match")
+            .flat_map(|x| x.split(','))
+            .map(str::trim)
+            .any(|x| x == "*" || x == etag || x.strip_prefix("W/") == Some(etag))
+    }
+}
+impl<'r> Responder<'r, 'static> for Asset {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'static> {
+        let mut response = if self.not_modified(req) {
+            Response::build().status(Status::NotModified).finalize()
+        } else {
+            let mut response = match self.body {
+                Body::File(file) => file.respond_to(req)?,
+                Body::Bytes(bytes) => bytes.respond_to(req)?,
+            };
+            if let Some(x) = self.content_type {
+                response.set_header(x);
+            }
+            if let Some(x) = self.encoding {
+                response.set_header(Header::new("Content-Encoding", x));
+            }
+            response
+        };
+        if self.varies {
+            response.set_header(Header::new("Vary", "Accept-Encoding"));
+        }
+        if let Some(x) = self.cache_control {
+            response.set_raw_header("Cache-Control", x);
+        }
+        if let Some(x) = self.etag {
+            response.set_raw_header("ETag", x);
+        }
+        Ok(response)
+    }
+}
+
+#[get("/", rank = 9)]
+pub async fn index(assets: State<'_, Assets>, accept: AcceptEncoding) -> Option<Asset> {
+    assets.open(Path::new(""), &accept).await
+}
+
+// Same rank `StaticFiles` used, so anything more specific wins.
+#[get("/<path..>", rank = 10)]
+pub async fn file(
+    path: PathBuf,
+    assets: State<'_, Assets>,
+    accept: AcceptEncoding,
+) -> Option<Asset> {
+    assets.open(&path, &accept).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::std::fs;
+    use ::std::time::{Duration, SystemTime};
+
+    #[test]
+    fn source_maps_are_recognised_compressed_or_not() {
+        assert!(is_source_map(Path::new("main.js.map")));
+        assert!(is_source_map(Path::new("js/main.js.map.gz")));
+        assert!(is_source_map(Path::new("main.js.map.br")));
+        assert!(!is_source_map(Path::new("main.js")));
+        assert!(!is_source_map(Path::new("main.js.gz")));
+        assert!(!is_source_map(Path::new("map")));
+    }
+
+    #[test]
+    fn compressed_copies_older_than_the_original_are_ignored() {
+        let dir = ::std::env::temp_dir().join(format!("fileshare-assets-{}", ::std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let original = dir.join("main.js");
+        let brotli = dir.join("main.js.br");
+        let gzip = dir.join("main.js.gz");
+
+        // A release build writes the original and its compressed copies.
+        fs::write(&original, "release").unwrap();
+        fs::write(&brotli, "release, compressed").unwrap();
+        fs::write(&gzip, "release, compressed").unwrap();
+        let names =
+            |x: Vec<(&'static str, PathBuf)>| x.into_iter().map(|(c, _)| c).collect::<Vec<_>>();
+        assert_eq!(names(compressed_variants(&original)), ["br", "gzip"]);
+
+        // Then a debug build rewrites the original, and nothing else.
+        fs::write(&original, "debug").unwrap();
+        fs::File::options()
+            .write(true)
+            .open(&original)
+            .unwrap()
+            .set_modified(SystemTime::now() + Duration::from_secs(60))
+            .unwrap();
+        assert!(compressed_variants(&original).is_empty());
+        let accept = AcceptEncoding::parse(::std::iter::once("gzip, br"));
+        assert!(accept.choose(compressed_variants(&original)).is_none());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,397 @@
+//! Taking over a listening socket from whoever started us.
+//! In dev mode, `fileshare-build` owns the socket, and hands it to each new
+//! server the way systemd does socket activation, with `LISTEN_FDS`.
+//! That way restarting doesn't close the socket, so nobody gets refused,
+//! and the old server can finish what it's doing while the new one takes over.
+//! This is only built into debug builds on unix, since it's only for dev.
+//!
+//! Rocket can't be given the socket itself: `launch` binds its own,
+//! from the address in its config, and there's no way to hand it a listener,
+//! or to swap one in once it's bound.
+//! So it gets a port on loopback, and connections to the inherited socket
+//! are forwarded to it byte for byte. TLS still happens in Rocket.
+//! Once Rocket's listening, we say so through `NOTIFY_SOCKET`,
+//! like a systemd `Type=notify` service, and start accepting.
+//! Rocket would think every request came from us,
+//! so the [`Forwarded`] fairing looks up who's really on the other end,
+//! and sets that as the request's remote address.
+//!
+//! On `SIGTERM`, we stop accepting, and close connections that aren't
+//! in the middle of something, then say `STOPPING=1`.
+//! A connection's busy while Rocket has a request on it that it hasn't
+//! answered, which the fairing tells us about.
+//! After that, we can't see through TLS, so it goes by who spoke last:
+//! a connection where the server spoke last, and has been quiet for [`IDLE`],
+//! is done, and one where the client starts talking again
+//! is starting another request, which the new server should get.
+//! Once every connection's closed, Rocket gets a Ctrl-C,
+//! so it shuts down the way it normally would.
+use ::rocket::fairing::{Fairing, Info, Kind};
+use ::rocket::{Data, Request, Response};
+use ::std::collections::BTreeMap;
+use ::std::ffi::OsString;
+use ::std::io::{self, Read, Write};
+use ::std::mem;
+use ::std::net::{Ipv4Addr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use ::std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use ::std::sync::atomic::{AtomicBool, AtomicI32, AtomicUsize, Ordering};
+use ::std::sync::{Arc, Mutex};
+use ::std::thread;
+use ::std::time::{Duration, Instant};
+
+/// Where inherited sockets start, as in `sd_listen_fds`.
+const LISTEN_FDS_START: RawFd = 3;
+/// How long the server has to have been quiet, after having the last word,
+/// for a connection to count as idle.
+const IDLE: Duration = Duration::from_millis(500);
+/// How often to look for idle connections while stopping,
+/// and for Rocket while starting.
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+static TERMINATED: AtomicBool = AtomicBool::new(false);
+/// The write end of a pipe the accept loop waits on, alongside the socket.
+static WAKE: AtomicI32 = AtomicI32::new(-1);
+/// Forwarded connections, by the port they come to Rocket from.
+static CONNECTIONS: Mutex<BTreeMap<u16, Arc<Tracked>>> = Mutex::new(BTreeMap::new());
+
+extern "C" fn terminate(_: ::libc::c_int) {
+    TERMINATED.store(true, Ordering::SeqCst);
+    let wake = WAKE.load(Ordering::SeqCst);
+    if wake >= 0 {
+        // Safe, since `write` is fine in a signal handler.
+        unsafe {
+            ::libc::write(wake, [0u8].as_ptr() as *const ::libc::c_void, 1);
+        }
+    }
+}
+
+/// The listening socket we were given, if there is one.
+/// It's only taken once, and the variables are cleared,
+/// so nothing we start thinks it's theirs.
+pub fn inherited() -> Option<TcpListener> {
+    let fds: i32 = ::std::env::var("LISTEN_FDS").ok()?.parse().ok()?;
+    // `fileshare-build` doesn't set this, since it can't know our PID in time.
+    if let Ok(pid) = ::std::env::var("LISTEN_PID") {
+        if pid.parse() != Ok(::std::process::id()) {
+            return None;
+        }
+    }
+    ::std::env::remove_var("LISTEN_FDS");
+    ::std::env::remove_var("LISTEN_PID");
+    ::std::env::remove_var("LISTEN_FDNAMES");
+    if fds < 1 {
+        return None;
+    }
+    // Safe, since whoever set `LISTEN_FDS` says it's ours.
+    Some(unsafe { TcpListener::from_raw_fd(LISTEN_FDS_START) })
+}
+
+/// Point Rocket at loopback, and forward `listener` to it once it's up.
+/// Call this before Rocket reads its config,
+/// and attach what it gives back.
+pub fn forward(listener: TcpListener) -> io::Result<Forwarded> {
+    let (reserved, port) = reserve()?;
+    ::std::env::set_var("ROCKET_ADDRESS", "127.0.0.1");
+    ::std::env::set_var("ROCKET_PORT", port.to_string());
+    let notify = ::std::env::var_os("NOTIFY_SOCKET");
+    ::std::env::remove_var("NOTIFY_SOCKET");
+    listener.set_nonblocking(true)?;
+    let (wake_read, wake_write) = pipe()?;
+    WAKE.store(wake_write.as_raw_fd(), Ordering::SeqCst);
+    // The handler needs it for as long as we're running.
+    mem::forget(wake_write);
+    // Safe, since the handler only touches atomics, and `write`s.
+    unsafe {
+        ::libc::signal(
+            ::libc::SIGTERM,
+            terminate as extern "C" fn(::libc::c_int) as ::libc::sighandler_t,
+        );
+    }
+    thread::spawn(move || {
+        while TcpStream::connect((Ipv4Addr::LOCALHOST, port)).is_err() {
+            thread::sleep(POLL_INTERVAL);
+        }
+        drop(reserved);
+        notify_state(notify.as_ref(), "READY=1");
+        accept(listener, port, wake_read, notify);
+    });
+    Ok(Forwarded)
+}
+
+/// Tells Rocket who it's really talking to,
+/// and us which connections it's in the middle of answering.
+pub struct Forwarded;
+
+/// The connection a request came in on, so the response can find it,
+/// since its remote address isn't ours anymore by then.
+struct Via(Option<Arc<Tracked>>);
+
+#[::rocket::async_trait]
+impl Fairing for Forwarded {
+    fn info(&self) -> Info {
+        Info {
+            name: "Inherited socket",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _: &Data) {
+        let tracked = request
+            .remote()
+            .filter(|x| x.ip().is_loopback())
+            .and_then(|x| CONNECTIONS.lock().unwrap().get(&x.port()).cloned());
+        if let Some(ref tracked) = tracked {
+            tracked.requests.fetch_add(1, Ordering::SeqCst);
+            request.set_remote(tracked.peer);
+        }
+        request.local_cache(|| Via(tracked));
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, _: &mut Response<'r>) {
+        if let Via(Some(ref tracked)) = request.local_cache(|| Via(None)) {
+            tracked.requests.fetch_sub(1, Ordering::SeqCst);
+            *tracked.answered.lock().unwrap() = Instant::now();
+        }
+    }
+}
+
+/// A loopback port that stays ours until Rocket binds it.
+/// The socket's bound with `SO_REUSEADDR`, but isn't listening,
+/// which on Linux keeps out everything but another `SO_REUSEADDR` socket,
+/// which is how Rocket binds. Elsewhere, it's just a port that was free.
+fn reserve() -> io::Result<(Option<OwnedFd>, u16)> {
+    // Safe, since everything's checked, and the socket's owned straight away.
+    unsafe {
+        let fd = ::libc::socket(::libc::AF_INET, ::libc::SOCK_STREAM, 0);
+        if fd == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        let socket = OwnedFd::from_raw_fd(fd);
+        let on: ::libc::c_int = 1;
+        let mut address: ::libc::sockaddr_in = mem::zeroed();
+        address.sin_family = ::libc::AF_INET as ::libc::sa_family_t;
+        address.sin_addr.s_addr = u32::from(Ipv4Addr::LOCALHOST).to_be();
+        let mut length = mem::size_of::<::libc::sockaddr_in>() as ::libc::socklen_t;
+        let failed = ::libc::fcntl(fd, ::libc::F_SETFD, ::libc::FD_CLOEXEC) == -1
+            || ::libc::setsockopt(
+                fd,
+                ::libc::SOL_SOCKET,
+                ::libc::SO_REUSEADDR,
+                &on as *const _ as *const ::libc::c_void,
+                mem::size_of_val(&on) as ::libc::socklen_t,
+            ) == -1
+            || ::libc::bind(fd, &address as *const _ as *const ::libc::sockaddr, length) == -1
+            || ::libc::getsockname(
+                fd,
+                &mut address as *mut _ as *mut ::libc::sockaddr,
+                &mut length,
+            ) == -1;
+        if failed {
+            return Err(io::Error::last_os_error());
+        }
+        let port = u16::from_be(address.sin_port);
+        if cfg!(target_os = "linux") {
+            Ok((Some(socket), port))
+        } else {
+            Ok((None, port))
+        }
+    }
+}
+
+fn pipe() -> io::Result<(OwnedFd, OwnedFd)> {
+    let mut fds = [0; 2];
+    // Safe, since the ends are owned as soon as they exist.
+    unsafe {
+        if ::libc::pipe(fds.as_mut_ptr()) == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        let ends = (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1]));
+        for &fd in &fds {
+            ::libc::fcntl(fd, ::libc::F_SETFD, ::libc::FD_CLOEXEC);
+        }
+        // Signals piling up mustn't block the handler.
+        ::libc::fcntl(fds[1], ::libc::F_SETFL, ::libc::O_NONBLOCK);
+        Ok(ends)
+    }
+}
+
+/// Tell whoever started us how it's going, if they asked.
+fn notify_state(path: Option<&OsString>, state: &str) {
+    if let Some(path) = path {
+        let sent = ::std::os::unix::net::UnixDatagram::unbound()
+            .and_then(|socket| socket.send_to(state.as_bytes(), path));
+        if let Err(e) = sent {
+            eprintln!("couldn't say {}: {}", state, e);
+        }
+    }
+}
+
+/// A forwarded connection, and who spoke last.
+struct Tracked {
+    client: TcpStream,
+    /// Who's really on the other end.
+    peer: SocketAddr,
+    /// Requests Rocket has started on, but not answered.
+    requests: AtomicUsize,
+    /// Whether the client spoke last, so it's waiting on the server.
+    waiting: AtomicBool,
+    /// When the server last spoke, or when the connection started.
+    answered: Mutex<Instant>,
+}
+impl Tracked {
+    fn idle(&self) -> bool {
+        self.requests.load(Ordering::SeqCst) == 0
+            && !self.waiting.load(Ordering::SeqCst)
+            && self.answered.lock().unwrap().elapsed() >= IDLE
+    }
+
+    fn close(&self) {
+        let _ = self.client.shutdown(Shutdown::Both);
+    }
+}
+
+fn accept(listener: TcpListener, port: u16, wake: OwnedFd, notify: Option<OsString>) -> ! {
+    while !TERMINATED.load(Ordering::SeqCst) {
+        let mut fds = [
+            ::libc::pollfd {
+                fd: listener.as_raw_fd(),
+                events: ::libc::POLLIN,
+                revents: 0,
+            },
+            ::libc::pollfd {
+                fd: wake.as_raw_fd(),
+                events: ::libc::POLLIN,
+                revents: 0,
+            },
+        ];
+        // Safe, since `fds` is what it says it is.
+        if unsafe { ::libc::poll(fds.as_mut_ptr(), fds.len() as ::libc::nfds_t, -1) } == -1 {
+            let e = io::Error::last_os_error();
+            if e.kind() != io::ErrorKind::Interrupted {
+                eprintln!("couldn't wait for connections: {}", e);
+                thread::sleep(POLL_INTERVAL);
+            }
+            continue;
+        }
+        if fds[0].revents == 0 {
+            continue;
+        }
+        match listener.accept() {
+            Ok((client, _)) => {
+                thread::spawn(move || {
+                    if let Err(e) = connection(client, port) {
+                        eprintln!("forwarding a connection failed: {}", e);
+                    }
+                });
+            }
+            // The next server got it first.
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => (),
+            Err(e) => {
+                eprintln!("couldn't accept a connection: {}", e);
+                thread::sleep(POLL_INTERVAL);
+            }
+        }
+    }
+    // Closing our copy of the socket leaves it open for the next server.
+    drop(listener);
+    let close_idle = || {
+        let connections = CONNECTIONS.lock().unwrap();
+        for x in connections.values().filter(|x| x.idle()) {
+            x.close();
+        }
+        connections.is_empty()
+    };
+    // Whatever's left is in the middle of something,
+    // and any new requests will be closed on, so the new server gets them.
+    let mut done = close_idle();
+    notify_state(notify.as_ref(), "STOPPING=1");
+    while !done {
+        thread::sleep(POLL_INTERVAL);
+        done = close_idle();
+    }
+    // Safe, and Rocket's Ctrl-C handling takes it from here.
+    // If it doesn't have any, that's the end of us, which is fine too.
+    unsafe {
+        ::libc::raise(::libc::SIGINT);
+    }
+    // In case Rocket caught it, but didn't stop.
+    thread::sleep(Duration::from_secs(10));
+    ::std::process::exit(0)
+}
+
+/// Forward a connection to Rocket, keeping track of it while it's open.
+fn connection(client: TcpStream, port: u16) -> io::Result<()> {
+    // Some platforms make accepted sockets non-blocking, like the listener.
+    client.set_nonblocking(false)?;
+    let server = TcpStream::connect((Ipv4Addr::LOCALHOST, port))?;
+    let key = server.local_addr()?.port();
+    let tracked = Arc::new(Tracked {
+        client: client.try_clone()?,
+        peer: client.peer_addr()?,
+        requests: AtomicUsize::new(0),
+        waiting: AtomicBool::new(false),
+        answered: Mutex::new(Instant::now()),
+    });
+    // Nothing reaches Rocket until the copying starts,
+    // so the fairing always finds it.
+    CONNECTIONS.lock().unwrap().insert(key, tracked.clone());
+    let copied = copy_both(client, server, &tracked);
+    CONNECTIONS.lock().unwrap().remove(&key);
+    copied
+}
+
+/// Copy both ways, until both sides are done.
+fn copy_both(client: TcpStream, server: TcpStream, tracked: &Arc<Tracked>) -> io::Result<()> {
+    let up = {
+        let (mut client, mut server, tracked) =
+            (client.try_clone()?, server.try_clone()?, tracked.clone());
+        thread::spawn(move || {
+            let copied = copy(&mut client, &mut server, || {
+                // A new request, once we're stopping, is the new server's.
+                if TERMINATED.load(Ordering::SeqCst)
+                    && !tracked.waiting.load(Ordering::SeqCst)
+                    && tracked.requests.load(Ordering::SeqCst) == 0
+                {
+                    tracked.close();
+                    return false;
+                }
+                tracked.waiting.store(true, Ordering::SeqCst);
+                true
+            });
+            let _ = server.shutdown(Shutdown::Write);
+            copied
+        })
+    };
+    let (mut client_write, mut server_read) = (client, server);
+    copy(&mut server_read, &mut client_write, || {
+        tracked.waiting.store(false, Ordering::SeqCst);
+        *tracked.answered.lock().unwrap() = Instant::now();
+        true
+    })?;
+    let _ = client_write.shutdown(Shutdown::Write);
+    up.join()
+        .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::Other, "panicked")))?;
+    Ok(())
+}
+
+/// Like `io::copy`, but `read` hears about every read before it's written,
+/// and can stop there.
+fn copy<R: Read, W: Write, F: FnMut() -> bool>(
+    from: &mut R,
+    to: &mut W,
+    mut read: F,
+) -> io::Result<()> {
+    let mut buf = [0; 16 * 1024];
+    loop {
+        let n = match from.read(&mut buf) {
+            Ok(0) => return Ok(()),
+            Ok(n) => n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        if !read() {
+            return Ok(());
+        }
+        to.write_all(&buf[..n])?;
+    }
+}
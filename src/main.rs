@@ -1,15 +1,45 @@
 //! Now that Rocket works on Stable, I *have* to give it a shot.
 use ::rocket::{get, launch};
-use ::rocket_contrib::serve::{crate_relative, StaticFiles};
+
+mod assets;
+#[cfg(all(unix, debug_assertions))]
+mod handoff;
 
 #[get("/")]
 fn hello() -> &'static str {
     "Hello, world!"
 }
 
+#[cfg(not(feature = "embed-assets"))]
+fn site() -> assets::Assets {
+    use ::rocket_contrib::serve::crate_relative;
+    // Deployed builds aren't next to their source,
+    // so the systemd unit from `fileshare-build package` says where the site is.
+    let static_dir = ::std::env::var_os("FILESHARE_STATIC")
+        .map(::std::path::PathBuf::from)
+        .unwrap_or_else(|| crate_relative!("/static").into());
+    assets::Assets::new(static_dir)
+}
+
+#[cfg(feature = "embed-assets")]
+fn site() -> assets::Assets {
+    assets::Assets::embedded()
+}
+
 #[launch]
 fn rocket() -> ::rocket::Rocket {
-    rocket::ignite()
-        .mount("/", StaticFiles::from(crate_relative!("/static")))
+    // `fileshare-build dev` hands us its socket, so restarts don't drop anyone.
+    #[cfg(all(unix, debug_assertions))]
+    if let Some(listener) = handoff::inherited() {
+        let forwarded = handoff::forward(listener).expect("couldn't forward the inherited socket");
+        return mount(rocket::ignite()).attach(forwarded);
+    }
+    mount(rocket::ignite())
+}
+
+fn mount(rocket: ::rocket::Rocket) -> ::rocket::Rocket {
+    rocket
+        .manage(site())
+        .mount("/", ::rocket::routes![assets::index, assets::file])
         .mount("/api", ::rocket::routes![hello])
 }
@@ -1,7 +1,27 @@
-const address = "wss://home.greenjaffaco.com:9000";
+// `reload-config.js` says which port the dev server's on, if there is one.
+// It's on the same host as the page, by whatever name the browser used.
+const dev_port = window.reload_config ? window.reload_config.port : null;
+const address = dev_port === null ? null : `wss://${location.hostname}:${dev_port}`;
 const reload_key = 'fileshare-dev-reload-token';
 const error_class = 'reload-error';
-var socket = new WebSocket(address);
+const session_key = 'fileshare-dev-session';
+const outdated_key = 'fileshare-dev-outdated';
+const status_id = 'reload-status';
+// Reports made while the socket's down wait for it, up to a point.
+const max_pending = 100;
+// Must match `protocol::PROTOCOL` in fileshare-build.
+const protocol = 1;
+const capabilities = ['reports'];
+// The server pings every 15 seconds, so this long without hearing
+// anything means the connection's dead, even if nobody said so.
+const idle_timeout = 45000;
+// Reconnecting waits twice as long each time, up to the max,
+// with some randomness, so a dozen tabs don't all retry at once.
+const backoff_min = 500;
+const backoff_max = 30000;
+var socket = address === null ? null : new WebSocket(address);
+let attempts = 0;
+let watchdog = null;
 function milliseconds(t) {
 	return new Promise(resolve => {
 		setTimeout(() => {
@@ -17,37 +37,269 @@ function make_error(text) {
 	return elem;
 }
 
-function on_message(event) {
-	let data = JSON.parse(event.data);
-	console.log(data);
-	if (data.RefreshPage) {
-		let refresh_token = JSON.parse(localStorage.getItem(reload_key));
-		if (data.RefreshPage !== refresh_token) {
-			localStorage.setItem(reload_key, JSON.stringify(data.RefreshPage));
-			location.reload();
-		} else {
-			console.log("Don't need to reload again.");
+function show_error(text) {
+	// Remove previous error messages,
+	// since we know that the server will regenerate them if necessary.
+	for (const elem of Array.from(document.getElementsByClassName(error_class))) {
+		document.body.removeChild(elem);
+	}
+	document.body.appendChild(make_error(text));
+}
+
+function refresh(token) {
+	let refresh_token = JSON.parse(localStorage.getItem(reload_key));
+	if (token !== refresh_token) {
+		localStorage.setItem(reload_key, JSON.stringify(token));
+		location.reload();
+	} else {
+		console.log("Don't need to reload again.");
+	}
+}
+
+// Whether we're connected to the dev server, in a corner of the page.
+function show_status(text) {
+	if (!document.body) {
+		document.addEventListener('DOMContentLoaded', () => show_status(text));
+		return;
+	}
+	let elem = document.getElementById(status_id);
+	if (text === null) {
+		if (elem) {
+			elem.remove();
 		}
-	} else if (data.DisplayError) {
-		// Remove previous error messages,
-		// since we know that the server will regenerate them if necessary.
-		for (const elem of document.getElementsByClassName(error_class)) {
-			document.body.removeChild(elem);
+		return;
+	}
+	if (!elem) {
+		elem = document.createElement('div');
+		elem.id = status_id;
+		elem.style.cssText = 'position: fixed; bottom: 0; right: 0; z-index: 2147483647; '
+			+ 'padding: 0.25em 0.5em; background: #333; color: #fff; '
+			+ 'font: 12px sans-serif; opacity: 0.8;';
+		document.body.appendChild(elem);
+	}
+	elem.textContent = text;
+}
+
+// Hang up if the server's gone quiet.
+function reset_watchdog() {
+	clearTimeout(watchdog);
+	watchdog = setTimeout(() => {
+		console.log("Reload server went quiet.");
+		socket.close();
+	}, idle_timeout);
+}
+
+// The server speaks some other protocol, so this script is stale.
+// Reloading should fetch a new one, but only try once,
+// in case it's the server that's stale.
+function outdated(server_protocol) {
+	let message = `reload.js speaks protocol ${protocol}, but the dev server speaks ${server_protocol}`;
+	if (sessionStorage.getItem(outdated_key) !== String(server_protocol)) {
+		sessionStorage.setItem(outdated_key, String(server_protocol));
+		location.reload();
+	} else {
+		show_error(message + "; restart whichever is older.");
+	}
+}
+
+function on_message(event) {
+	reset_watchdog();
+	let message = JSON.parse(event.data);
+	let data = message.data;
+	switch (message.type) {
+	case 'hello':
+		if (data.protocol !== protocol) {
+			outdated(data.protocol);
+			return;
 		}
-		document.body.appendChild(make_error(data.DisplayError));
+		sessionStorage.removeItem(outdated_key);
+		attempts = 0;
+		show_status(null);
+		break;
+	case 'outdated':
+		outdated(data.protocol);
+		break;
+	case 'ping':
+		send({ type: 'pong', data: data });
+		break;
+	case 'refresh_page':
+		refresh(data);
+		break;
+	case 'display_error':
+		show_error(data);
+		break;
+	case 'backend_down':
+		show_error("Server down: " + data);
+		break;
+	case 'backend_up':
+		refresh(data);
+		break;
+	default:
+		console.log("Unknown message from the reload server:", message);
 	}
 }
 function on_error(event) {}
 
+// Which tab this is, so the terminal can tell them apart.
+// It survives reloads, but not closing the tab.
+function session_id() {
+	let id = sessionStorage.getItem(session_key);
+	if (!id) {
+		id = Math.random().toString(16).slice(2, 8);
+		sessionStorage.setItem(session_key, id);
+	}
+	return id;
+}
+
+let pending = [];
+
+function send(message) {
+	if (socket && socket.readyState === WebSocket.OPEN) {
+		socket.send(JSON.stringify(message));
+	} else if (pending.length < max_pending) {
+		pending.push(message);
+	}
+}
+
+// Tell the dev server about something going wrong in the page.
+function report(kind, message, source, stack) {
+	send({ type: 'report', data: {
+		kind: kind,
+		message: String(message),
+		source: source || null,
+		stack: stack || null,
+	} });
+}
+
+function describe(value) {
+	if (value instanceof Error) {
+		return value.toString();
+	}
+	try {
+		return typeof value === 'string' ? value : JSON.stringify(value);
+	} catch (e) {
+		return String(value);
+	}
+}
+
+// Port callbacks run outside of Elm, and Elm throws if `send` gets a value
+// it can't decode, so both get reported as port errors.
+function watch_ports(app) {
+	for (const [name, port] of Object.entries((app && app.ports) || {})) {
+		if (port.subscribe) {
+			const subscribe = port.subscribe;
+			const unsubscribe = port.unsubscribe;
+			const wrapped = new Map();
+			port.subscribe = callback => {
+				const wrapper = value => {
+					try {
+						return callback(value);
+					} catch (e) {
+						report('elm-port', `port ${name}: ${describe(e)}`, null, e && e.stack);
+						throw e;
+					}
+				};
+				wrapped.set(callback, wrapper);
+				subscribe(wrapper);
+			};
+			port.unsubscribe = callback => {
+				unsubscribe(wrapped.get(callback) || callback);
+				wrapped.delete(callback);
+			};
+		}
+		if (port.send) {
+			const port_send = port.send;
+			port.send = value => {
+				try {
+					port_send(value);
+				} catch (e) {
+					report('elm-port', `port ${name}: ${describe(e)}`, null, e && e.stack);
+					throw e;
+				}
+			};
+		}
+	}
+	return app;
+}
+
+// Every program's `init`, however deep in modules it is.
+function watch_inits(exports) {
+	for (const value of Object.values(exports || {})) {
+		if (value && typeof value.init === 'function') {
+			const init = value.init;
+			value.init = flags => watch_ports(init(flags));
+		}
+		if (value && typeof value === 'object') {
+			watch_inits(value);
+		}
+	}
+}
+
+// Report what goes wrong in the page to the dev server.
+// Only called when there is one, so release builds
+// don't get their globals patched for reports nobody will read.
+function watch_page() {
+	const console_error = console.error;
+	console.error = function (...args) {
+		let error = args.find(x => x instanceof Error);
+		report('console.error', args.map(describe).join(' '), null, error && error.stack);
+		console_error.apply(console, args);
+	};
+
+	window.addEventListener('error', event => {
+		let source = event.filename ? `${event.filename}:${event.lineno}:${event.colno}` : null;
+		report('exception', event.message, source, event.error && event.error.stack);
+	});
+
+	window.addEventListener('unhandledrejection', event => {
+		let reason = event.reason;
+		report('rejection', describe(reason), null, reason && reason.stack);
+	});
+
+	// This is loaded before the Elm bundle,
+	// so we can catch it setting `window.Elm`, and watch its ports.
+	let elm_exports = window.Elm;
+	Object.defineProperty(window, 'Elm', {
+		configurable: true,
+		get() {
+			return elm_exports;
+		},
+		set(value) {
+			elm_exports = value;
+			watch_inits(value);
+		},
+	});
+}
+
 async function on_close(event) {
-	console.log("Reload server connection closed. Retrying...");
-	socket.close();
+	clearTimeout(watchdog);
+	let cap = Math.min(backoff_max, backoff_min * 2 ** attempts);
+	// Somewhere between half and all of it.
+	let delay = cap / 2 + Math.random() * cap / 2;
+	attempts += 1;
+	console.log(`Reload server connection closed. Retrying in ${Math.round(delay)}ms...`);
+	show_status(`dev server disconnected, retrying in ${Math.ceil(delay / 1000)}s`);
+	await milliseconds(delay);
+	show_status("dev server disconnected, reconnecting...");
 	socket = new WebSocket(address);
 	init_socket(socket);
 }
 
 function on_open(event) {
 	console.log("Socket open.");
+	reset_watchdog();
+	send({ type: 'hello', data: {
+		protocol: protocol,
+		session: session_id(),
+		user_agent: navigator.userAgent,
+		page: location.pathname,
+		capabilities: capabilities,
+	} });
+	let waiting = pending;
+	pending = [];
+	for (const message of waiting) {
+		send(message);
+	}
 }
 
 function init_socket(socket) {
@@ -57,4 +309,7 @@ function init_socket(socket) {
 	socket.addEventListener('open', on_open);
 }
 
-init_socket(socket);
+if (socket) {
+	init_socket(socket);
+	watch_page();
+}